@@ -0,0 +1,456 @@
+use anyhow::Result;
+use clap::{Args, Subcommand, ValueHint};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs, io,
+    io::{BufRead, BufReader, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::env::ProcessEnv;
+
+#[derive(Error, Debug)]
+pub enum AgentError {
+    #[error("cannot connect to the agent at '{0}' - is `kvenv agent` running?")]
+    Connect(PathBuf, #[source] io::Error),
+    #[error("another agent is already listening at '{0}'")]
+    Running(PathBuf),
+    #[error("cannot listen at '{0}'")]
+    Listen(PathBuf, #[source] io::Error),
+    #[error("cannot communicate with the agent")]
+    Io(#[from] io::Error),
+    #[error("the message from the agent is invalid")]
+    Protocol(#[from] serde_json::Error),
+    #[error("the agent does not have environment '{0}' (or it has expired)")]
+    NotFound(String),
+    #[error("the agent cannot handle the request: {0}")]
+    Failed(String),
+    #[error(
+        "refusing to use '{0}' - it must be a directory owned by the current user with mode 0700"
+    )]
+    UnsafeDir(PathBuf),
+    #[error("the other side of the agent socket is run by another user (uid {0})")]
+    ForeignUser(libc::uid_t),
+}
+
+#[derive(Args, Clone, Debug, Default)]
+pub struct SocketConfig {
+    /// Path to the socket of the agent. Defaults to `$XDG_RUNTIME_DIR/kvenv/agent.sock`, or
+    /// `/tmp/kvenv-$UID/agent.sock` if `XDG_RUNTIME_DIR` is not set.
+    #[arg(
+        long,
+        env = "KVENV_AGENT_SOCKET",
+        value_parser,
+        value_hint = ValueHint::FilePath,
+        global = true
+    )]
+    agent_socket: Option<PathBuf>,
+}
+
+impl SocketConfig {
+    fn path(&self) -> PathBuf {
+        if let Some(path) = &self.agent_socket {
+            return path.clone();
+        }
+        let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(runtime) => PathBuf::from(runtime).join("kvenv"),
+            None => std::env::temp_dir().join(format!("kvenv-{}", unsafe { libc::getuid() })),
+        };
+        dir.join("agent.sock")
+    }
+}
+
+/// Holds environments stored with `cache --agent` in memory and passes them to
+/// `run-with --agent`, so that nothing is written to the disk. The agent listens on a Unix socket
+/// accessible only by the current user.
+#[derive(Args, Debug)]
+pub struct Agent {
+    #[command(subcommand)]
+    command: Option<AgentCommand>,
+
+    #[command(flatten)]
+    socket: SocketConfig,
+}
+
+#[derive(Subcommand, Debug)]
+enum AgentCommand {
+    /// Removes all the environments (or the specified one) from the running agent.
+    Flush {
+        /// The name of the environment to remove.
+        #[arg(name = "NAME")]
+        name: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Request {
+    Put {
+        name: String,
+        env: Value,
+        /// Seconds until the environment expires.
+        ttl: Option<u64>,
+    },
+    Get {
+        name: String,
+    },
+    Flush {
+        name: Option<String>,
+    },
+    /// The integrity key of the cached env files.
+    Key,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum Response {
+    Ok,
+    Env(Value),
+    /// The integrity key, hex-encoded.
+    Key(String),
+    NotFound,
+    Error(String),
+}
+
+struct Entry {
+    env: Value,
+    expires: Option<Instant>,
+}
+
+/// The environments held by the agent.
+struct Store {
+    entries: HashMap<String, Entry>,
+    /// Generated when the agent starts and never written anywhere.
+    key: [u8; 32],
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            key: rand::random(),
+        }
+    }
+}
+
+impl Store {
+    fn handle(&mut self, request: Request, now: Instant) -> Response {
+        self.entries
+            .retain(|_, e| e.expires.is_none_or(|t| t > now));
+        match request {
+            Request::Put { name, env, ttl } => {
+                let expires = ttl.map(|s| now + Duration::from_secs(s));
+                self.entries.insert(name, Entry { env, expires });
+                Response::Ok
+            }
+            Request::Get { name } => match self.entries.get(&name) {
+                Some(entry) => Response::Env(entry.env.clone()),
+                None => Response::NotFound,
+            },
+            Request::Flush { name: Some(name) } => {
+                self.entries.remove(&name);
+                Response::Ok
+            }
+            Request::Flush { name: None } => {
+                self.entries.clear();
+                Response::Ok
+            }
+            Request::Key => Response::Key(hex::encode(self.key)),
+        }
+    }
+}
+
+fn send(stream: &UnixStream, message: &impl Serialize) -> Result<(), AgentError> {
+    let mut data = serde_json::to_vec(message)?;
+    data.push(b'\n');
+    let mut stream = stream;
+    stream.write_all(&data)?;
+    Ok(())
+}
+
+fn receive<T: for<'de> Deserialize<'de>>(stream: &UnixStream) -> Result<Option<T>, AgentError> {
+    let mut line = String::new();
+    if BufReader::new(stream).read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&line)?))
+}
+
+/// The user id of the process on the other side of the socket.
+#[cfg(target_os = "linux")]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let cred_ptr = &mut cred as *mut libc::ucred as *mut libc::c_void;
+    let fd = stream.as_raw_fd();
+    match unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_PEERCRED, cred_ptr, &mut len) } {
+        0 => Ok(cred.uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// The user id of the process on the other side of the socket.
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<libc::uid_t> {
+    let (mut uid, mut gid) = (0, 0);
+    match unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+        0 => Ok(uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Only the user running the agent can talk to it, also if the socket is accessible by others.
+fn check_peer(stream: &UnixStream) -> Result<(), AgentError> {
+    match peer_uid(stream)? {
+        uid if uid == unsafe { libc::getuid() } => Ok(()),
+        uid => Err(AgentError::ForeignUser(uid)),
+    }
+}
+
+fn handle_connection(stream: UnixStream, store: &Mutex<Store>) -> Result<(), AgentError> {
+    check_peer(&stream)?;
+    let response = match receive(&stream) {
+        Ok(Some(request)) => store.lock().unwrap().handle(request, Instant::now()),
+        // Closed without a request, e.g. when another agent checks whether this one is running
+        Ok(None) => return Ok(()),
+        Err(e) => Response::Error(e.to_string()),
+    };
+    send(&stream, &response)
+}
+
+/// Creates the private directory of the socket and removes a socket left by a previous agent. An
+/// existing directory has to be private as well, as the socket could be replaced otherwise.
+fn prepare_socket(path: &Path) -> Result<(), AgentError> {
+    let listen_error = |e| AgentError::Listen(path.to_owned(), e);
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    if !dir.exists() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .map_err(listen_error)?;
+    }
+    let meta = fs::symlink_metadata(dir).map_err(listen_error)?;
+    let is_private = meta.is_dir() && meta.mode() & 0o777 == 0o700;
+    if !is_private || meta.uid() != unsafe { libc::getuid() } {
+        return Err(AgentError::UnsafeDir(dir.to_owned()));
+    }
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(AgentError::Running(path.to_owned()));
+        }
+        fs::remove_file(path).map_err(listen_error)?;
+    }
+    Ok(())
+}
+
+fn serve(path: &Path) -> Result<(), AgentError> {
+    prepare_socket(path)?;
+    let listener = UnixListener::bind(path).map_err(|e| AgentError::Listen(path.to_owned(), e))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+        .map_err(|e| AgentError::Listen(path.to_owned(), e))?;
+    println!("{}", path.display());
+
+    let store = Arc::new(Mutex::new(Store::default()));
+    for stream in listener.incoming() {
+        let store = store.clone();
+        match stream {
+            Ok(stream) => {
+                thread::spawn(move || {
+                    if let Err(e) = handle_connection(stream, &store) {
+                        warn!(
+                            error = &e as &dyn std::error::Error,
+                            "cannot handle a connection"
+                        );
+                    }
+                });
+            }
+            Err(e) => warn!(
+                error = &e as &dyn std::error::Error,
+                "cannot accept a connection"
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn call(socket: &SocketConfig, request: &Request) -> Result<Response, AgentError> {
+    let path = socket.path();
+    let stream = UnixStream::connect(&path).map_err(|e| AgentError::Connect(path, e))?;
+    check_peer(&stream)?;
+    send(&stream, request)?;
+    match receive(&stream)? {
+        Some(Response::Error(e)) => Err(AgentError::Failed(e)),
+        Some(response) => Ok(response),
+        None => Err(AgentError::Failed("no response".to_string())),
+    }
+}
+
+/// Stores the environment in the agent under the name, replacing the previous one.
+pub fn store_env(
+    socket: &SocketConfig,
+    name: &str,
+    env: &ProcessEnv,
+    ttl: Option<Duration>,
+) -> Result<()> {
+    let request = Request::Put {
+        name: name.to_string(),
+        env: serde_json::to_value(env)?,
+        ttl: ttl.map(|t| t.as_secs().max(1)),
+    };
+    call(socket, &request)?;
+    Ok(())
+}
+
+/// Loads the environment stored in the agent under the name.
+pub fn load_env(socket: &SocketConfig, name: &str) -> Result<ProcessEnv> {
+    let request = Request::Get {
+        name: name.to_string(),
+    };
+    match call(socket, &request)? {
+        Response::Env(env) => Ok(serde_json::from_value(env)?),
+        _ => Err(AgentError::NotFound(name.to_string()).into()),
+    }
+}
+
+/// Removes the environment (or all of them) from the agent.
+pub fn flush(socket: &SocketConfig, name: Option<String>) -> Result<()> {
+    call(socket, &Request::Flush { name })?;
+    Ok(())
+}
+
+/// Gets the integrity key of the cached env files from the agent.
+pub fn integrity_key(socket: &SocketConfig) -> Result<Vec<u8>> {
+    match call(socket, &Request::Key)? {
+        Response::Key(key) => Ok(hex::decode(key).map_err(|e| AgentError::Failed(e.to_string()))?),
+        _ => Err(AgentError::Failed("unexpected response".to_string()).into()),
+    }
+}
+
+pub fn run_agent(cfg: Agent) -> Result<()> {
+    match cfg.command {
+        Some(AgentCommand::Flush { name }) => flush(&cfg.socket, name),
+        None => Ok(serve(&cfg.socket.path())?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(name: &str, ttl: Option<u64>) -> Request {
+        Request::Put {
+            name: name.to_string(),
+            env: Value::String(name.to_string()),
+            ttl,
+        }
+    }
+
+    fn get(name: &str) -> Request {
+        Request::Get {
+            name: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn expires_and_flushes_entries() {
+        let mut store = Store::default();
+        let now = Instant::now();
+        let env = |name: &str| Response::Env(Value::String(name.to_string()));
+
+        assert_eq!(Response::Ok, store.handle(put("a", None), now));
+        assert_eq!(Response::Ok, store.handle(put("b", Some(60)), now));
+        assert_eq!(Response::Ok, store.handle(put("c", Some(60)), now));
+        assert_eq!(env("b"), store.handle(get("b"), now));
+
+        let later = now + Duration::from_secs(61);
+        assert_eq!(Response::NotFound, store.handle(get("b"), later));
+        assert_eq!(env("a"), store.handle(get("a"), later));
+
+        let flush = Request::Flush {
+            name: Some("a".to_string()),
+        };
+        assert_eq!(Response::Ok, store.handle(flush, now));
+        assert_eq!(Response::NotFound, store.handle(get("a"), now));
+        assert_eq!(Response::Ok, store.handle(put("d", None), now));
+        assert_eq!(
+            Response::Ok,
+            store.handle(Request::Flush { name: None }, now)
+        );
+        assert_eq!(Response::NotFound, store.handle(get("d"), now));
+    }
+
+    #[test]
+    fn serves_environments() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = SocketConfig {
+            agent_socket: Some(dir.path().join("private").join("agent.sock")),
+        };
+        let path = socket.path();
+        thread::spawn(move || serve(&path));
+        while !socket.path().exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let mode = fs::metadata(dir.path().join("private"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(0o700, mode & 0o777);
+        let mode = fs::metadata(socket.path()).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        assert!(matches!(
+            prepare_socket(&socket.path()),
+            Err(AgentError::Running(_))
+        ));
+
+        let env = ProcessEnv::fresh(
+            vec![],
+            vec![("KV".to_string(), "secret".to_string())],
+            vec![],
+        );
+        store_env(&socket, "app", &env, None).unwrap();
+        let loaded = load_env(&socket, "app").unwrap();
+        assert_eq!(env.secrets(), loaded.secrets());
+
+        flush(&socket, None).unwrap();
+        assert!(load_env(&socket, "app").is_err());
+
+        let key = integrity_key(&socket).unwrap();
+        assert_eq!(32, key.len());
+        assert_eq!(key, integrity_key(&socket).unwrap());
+    }
+
+    #[test]
+    fn refuses_foreign_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = dir.path().join("shared");
+        fs::DirBuilder::new().mode(0o755).create(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(matches!(
+            prepare_socket(&shared.join("agent.sock")),
+            Err(AgentError::UnsafeDir(_))
+        ));
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o700)).unwrap();
+        prepare_socket(&shared.join("agent.sock")).unwrap();
+
+        let (a, _b) = UnixStream::pair().unwrap();
+        assert_eq!(unsafe { libc::getuid() }, peer_uid(&a).unwrap());
+        check_peer(&a).unwrap();
+    }
+}
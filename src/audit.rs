@@ -0,0 +1,240 @@
+use clap::{Args, ValueHint};
+use serde::Serialize;
+use std::{
+    ffi::CString,
+    fs,
+    io::{self, Write},
+    os::unix::{fs::OpenOptionsExt, process::ExitStatusExt},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{Mutex, OnceLock},
+    time::SystemTime,
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("cannot open the audit log '{0}'")]
+    Open(PathBuf, #[source] io::Error),
+    #[error("cannot write to the audit log")]
+    Write(#[from] io::Error),
+}
+
+#[derive(Args, Debug)]
+pub struct AuditConfig {
+    /// Append a JSON line to the file for every secret read from the store, every environment
+    /// passed to a command and every command exit. Values of the secrets are never logged.
+    #[arg(
+        long,
+        env = "KVENV_AUDIT_LOG",
+        value_parser,
+        value_hint = ValueHint::FilePath,
+        global = true
+    )]
+    audit_log: Option<PathBuf>,
+
+    /// Send the audit records to syslog (facility `authpriv`).
+    #[arg(long, env = "KVENV_AUDIT_SYSLOG", global = true)]
+    audit_syslog: bool,
+}
+
+/// An audited action.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    /// A secret was read from the store.
+    SecretRead {
+        /// `aws`, `azure`, `google` or `vault`.
+        store: &'static str,
+        /// The Key Vault address, the project or the Vault address and mount.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        location: Option<&'a str>,
+        secret: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        version: Option<&'a str>,
+    },
+    /// An environment was prepared from the secrets (or loaded from the cache).
+    Environment {
+        source: &'a str,
+        variables: Vec<&'a str>,
+    },
+    /// The command run in the environment exited.
+    CommandExit {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status: Option<i32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        signal: Option<i32>,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: String,
+    pid: u32,
+    uid: u32,
+    /// The `kvenv` subcommand.
+    kvenv: &'a str,
+    /// The command run in the environment, if any.
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    command: &'a [String],
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+struct AuditLog {
+    file: Option<Mutex<fs::File>>,
+    syslog: bool,
+    kvenv: String,
+    command: Mutex<Vec<String>>,
+}
+
+impl AuditLog {
+    fn open(path: Option<&Path>, syslog: bool, kvenv: &str) -> Result<Self, AuditError> {
+        let file = path
+            .map(|path| {
+                fs::OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .mode(0o600)
+                    .open(path)
+                    .map_err(|e| AuditError::Open(path.to_owned(), e))
+            })
+            .transpose()?;
+        if syslog {
+            unsafe { libc::openlog(c"kvenv".as_ptr(), libc::LOG_PID, libc::LOG_AUTHPRIV) };
+        }
+        Ok(Self {
+            file: file.map(Mutex::new),
+            syslog,
+            kvenv: kvenv.to_string(),
+            command: Mutex::new(vec![]),
+        })
+    }
+
+    fn record(&self, event: Event) -> Result<(), AuditError> {
+        let command = self.command.lock().unwrap();
+        let record = Record {
+            time: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            pid: std::process::id(),
+            uid: unsafe { libc::getuid() },
+            kvenv: &self.kvenv,
+            command: &command,
+            event,
+        };
+        let line = serde_json::to_string(&record).map_err(io::Error::from)?;
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            file.write_all(format!("{line}\n").as_bytes())?;
+            file.flush()?;
+        }
+        if self.syslog {
+            let message = CString::new(line).map_err(io::Error::from)?;
+            unsafe { libc::syslog(libc::LOG_INFO, c"%s".as_ptr(), message.as_ptr()) };
+        }
+        Ok(())
+    }
+}
+
+static AUDIT_LOG: OnceLock<AuditLog> = OnceLock::new();
+
+/// Enables the audit log (if configured) for the rest of the process. `kvenv` is the name of the
+/// subcommand being run.
+pub fn init(cfg: &AuditConfig, kvenv: &str) -> Result<(), AuditError> {
+    if cfg.audit_log.is_none() && !cfg.audit_syslog {
+        return Ok(());
+    }
+    let log = AuditLog::open(cfg.audit_log.as_deref(), cfg.audit_syslog, kvenv)?;
+    let _ = AUDIT_LOG.set(log);
+    Ok(())
+}
+
+/// Sets the command run in the environment, which is added to all the following records.
+pub fn set_command(command: &[String]) {
+    if let Some(log) = AUDIT_LOG.get() {
+        *log.command.lock().unwrap() = command.to_vec();
+    }
+}
+
+/// Records the event, if the audit log is enabled. Failing to record it is an error, so that
+/// secrets are never read without a trace.
+pub fn record(event: Event) -> Result<(), AuditError> {
+    match AUDIT_LOG.get() {
+        Some(log) => log.record(event),
+        None => Ok(()),
+    }
+}
+
+/// Records that a secret was read, see `Event::SecretRead`.
+pub fn secret_read(
+    store: &'static str,
+    location: Option<&str>,
+    secret: &str,
+    version: Option<&str>,
+) -> Result<(), AuditError> {
+    record(Event::SecretRead {
+        store,
+        location,
+        secret,
+        version,
+    })
+}
+
+/// Records the environment prepared from `source`, see `Event::Environment`.
+pub fn environment(source: &str, secrets: &[(String, String)]) -> Result<(), AuditError> {
+    record(Event::Environment {
+        source,
+        variables: secrets.iter().map(|(k, _)| k.as_str()).collect(),
+    })
+}
+
+/// Records the exit of the command, see `Event::CommandExit`.
+pub fn command_exit(status: &ExitStatus) -> Result<(), AuditError> {
+    record(Event::CommandExit {
+        status: status.code(),
+        signal: status.signal(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn writes_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(Some(&path), false, "run-in").unwrap();
+
+        let secret = Event::SecretRead {
+            store: "aws",
+            location: None,
+            secret: "app",
+            version: Some("v1"),
+        };
+        log.record(secret).unwrap();
+        *log.command.lock().unwrap() = vec!["env".to_string()];
+        log.record(Event::CommandExit {
+            status: Some(0),
+            signal: None,
+        })
+        .unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let records: Vec<Value> = contents
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(2, records.len());
+        assert_eq!(json!("secret-read"), records[0]["event"]);
+        assert_eq!(json!("run-in"), records[0]["kvenv"]);
+        assert_eq!(json!("app"), records[0]["secret"]);
+        assert_eq!(json!("v1"), records[0]["version"]);
+        assert_eq!(None, records[0].get("command"));
+        assert_eq!(None, records[0].get("location"));
+        assert_eq!(json!("command-exit"), records[1]["event"]);
+        assert_eq!(json!(["env"]), records[1]["command"]);
+        assert_eq!(json!(0), records[1]["status"]);
+        assert!(records[1]["time"].as_str().unwrap().ends_with('Z'));
+    }
+}
@@ -1,10 +1,20 @@
-use anyhow::Result;
-use clap::{arg, command, Args, ValueHint};
-use std::{fs, io, path::PathBuf};
+use anyhow::{bail, Result};
+use clap::{Args, ValueEnum, ValueHint};
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use tempfile::NamedTempFile;
 use thiserror::Error;
 
+use crate::agent::{self, SocketConfig};
 use crate::env;
+use crate::integrity::IntegrityConfig;
+use crate::keyring_store::{self, KeyringConfig};
+use crate::run::parse_duration;
 
 #[derive(Error, Debug)]
 pub enum CacheError {
@@ -14,6 +24,8 @@ pub enum CacheError {
     Io(#[from] io::Error),
     #[error("cannot store the resulting env file - there was a problem during serialization")]
     Serialization(#[from] serde_json::Error),
+    #[error("refusing to write the env file to '{0}' - it is a symbolic link")]
+    Symlink(PathBuf),
 }
 
 /// Caches the environment variables from KeyVault into local file.
@@ -29,6 +41,44 @@ pub struct Cache {
     /// downloaded.
     #[arg(short = 'e', long)]
     snapshot_env: bool,
+
+    /// Where the environment is stored.
+    #[arg(long, value_enum, default_value_t)]
+    store: CacheStore,
+
+    #[command(flatten)]
+    keyring: KeyringConfig,
+
+    #[command(flatten)]
+    integrity: IntegrityConfig,
+
+    #[command(flatten)]
+    agent: AgentConfig,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CacheStore {
+    /// A JSON file, whose path is printed.
+    #[default]
+    File,
+    /// The OS keyring (see `keyring-backend`), the handle of the environment is printed. Use it
+    /// with `run-with --handle`.
+    Keyring,
+}
+
+#[derive(Args, Debug)]
+pub struct AgentConfig {
+    /// Store the environment under this name in the running `kvenv agent` instead of a file.
+    #[arg(long, group = "output")]
+    agent: Option<String>,
+
+    /// How long the agent keeps the environment (e.g. `30m` or `8h`). By default, it's kept until
+    /// flushed or until the agent stops.
+    #[arg(long, value_parser = parse_duration, requires = "agent")]
+    ttl: Option<Duration>,
+
+    #[command(flatten)]
+    socket: SocketConfig,
 }
 
 #[derive(Args, Debug)]
@@ -44,37 +94,95 @@ pub struct OutputFileConfig {
     output_dir: Option<PathBuf>,
 }
 
+/// The files are readable only by the owner, as they contain the secrets.
+const FILE_MODE: u32 = 0o600;
+
 enum OutputFile {
-    Direct(fs::File, PathBuf),
+    /// A temporary file in the directory of the target path, renamed to it once written, so that
+    /// the target never has partial contents.
+    Direct(NamedTempFile, PathBuf),
     Temp(NamedTempFile),
 }
 
+fn refuse_symlink(path: &Path) -> Result<(), CacheError> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_symlink() => Err(CacheError::Symlink(path.to_owned())),
+        _ => Ok(()),
+    }
+}
+
+/// The directory of the file, where the temporary file replacing it is created.
+fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    }
+}
+
+/// Renames the written temporary file to the path.
+fn persist(t: NamedTempFile, path: &Path) -> Result<(), CacheError> {
+    // The path might have been replaced in the meantime
+    refuse_symlink(path)?;
+    t.persist(path).map_err(|e| CacheError::Io(e.error))?;
+    Ok(())
+}
+
+/// Writes the file readable only by the owner, through a temporary file renamed to it once
+/// written, like the cache files.
+pub fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    refuse_symlink(path)?;
+    let mut t = tempfile::Builder::new()
+        .prefix(".kvenv-")
+        .tempfile_in(parent_dir(path))?;
+    fs::set_permissions(t.path(), fs::Permissions::from_mode(FILE_MODE))?;
+    t.write_all(contents)?;
+    t.as_file().sync_all()?;
+    persist(t, path)?;
+    Ok(())
+}
+
 fn get_output_file(cfg: OutputFileConfig) -> Result<OutputFile> {
-    if let Some(f) = cfg.output_file {
-        let file = fs::File::create(&f).map_err(CacheError::Io)?;
-        Ok(OutputFile::Direct(file, f))
+    let mut b = tempfile::Builder::new();
+    b.prefix("kvenv-").suffix(".json").rand_bytes(5);
+    let (file, target) = if let Some(f) = cfg.output_file {
+        refuse_symlink(&f)?;
+        (b.prefix(".kvenv-").tempfile_in(parent_dir(&f)), Some(f))
+    } else if let Some(d) = cfg.output_dir {
+        (b.tempfile_in(d), None)
     } else {
-        let mut b = tempfile::Builder::new();
-        b.prefix("kvenv-").suffix(".json").rand_bytes(5);
-        let file = if let Some(d) = cfg.output_dir {
-            b.tempfile_in(d)
-        } else {
-            b.tempfile()
-        };
-        let file = file.map_err(CacheError::Io)?;
-        Ok(OutputFile::Temp(file))
+        (b.tempfile(), None)
+    };
+    let file = file.map_err(CacheError::Io)?;
+    fs::set_permissions(file.path(), fs::Permissions::from_mode(FILE_MODE))
+        .map_err(CacheError::Io)?;
+    match target {
+        Some(f) => Ok(OutputFile::Direct(file, f)),
+        None => Ok(OutputFile::Temp(file)),
     }
 }
 
-fn store_env(e: env::ProcessEnv, out_file: OutputFile) -> Result<PathBuf> {
+/// The contents of the cache file: the environment, where it comes from and the integrity key.
+struct Contents<'a> {
+    env: env::ProcessEnv,
+    source: &'a str,
+    key: Option<&'a [u8]>,
+}
+
+fn write_env(c: &Contents, t: &mut NamedTempFile) -> Result<(), CacheError> {
+    c.env.to_writer(t.as_file_mut(), c.source, c.key)?;
+    t.as_file().sync_all()?;
+    Ok(())
+}
+
+fn store_env(c: Contents, out_file: OutputFile) -> Result<PathBuf> {
     match out_file {
-        OutputFile::Direct(f, p) => {
-            e.to_writer(f).map_err(CacheError::Serialization)?;
+        OutputFile::Direct(mut t, p) => {
+            write_env(&c, &mut t)?;
+            persist(t, &p)?;
             Ok(p)
         }
         OutputFile::Temp(mut t) => {
-            e.to_writer(t.as_file_mut())
-                .map_err(CacheError::Serialization)?;
+            write_env(&c, &mut t)?;
             let (_, p) = t.keep().map_err(|e| CacheError::Io(e.error))?;
             Ok(p.as_path().to_owned())
         }
@@ -82,20 +190,40 @@ fn store_env(e: env::ProcessEnv, out_file: OutputFile) -> Result<PathBuf> {
 }
 
 pub fn run_cache(c: Cache) -> Result<()> {
+    let has_output_file = c.output_file.output_file.is_some() || c.output_file.output_dir.is_some();
+    if c.store == CacheStore::Keyring && (has_output_file || c.agent.agent.is_some()) {
+        bail!("`--store keyring` cannot be used with `--output-file`, `--output-dir` or `--agent`");
+    }
+
+    let source = c.env.describe();
     let cached_env = env::download_env(c.env, c.snapshot_env).map_err(CacheError::Load)?;
+    if let Some(name) = &c.agent.agent {
+        return agent::store_env(&c.agent.socket, name, &cached_env, c.agent.ttl);
+    }
+    if c.store == CacheStore::Keyring {
+        println!(
+            "{}",
+            keyring_store::store_env(&c.keyring, &cached_env, &source)?
+        );
+        return Ok(());
+    }
+    let key = c.integrity.key(&c.agent.socket)?;
+    let contents = Contents {
+        env: cached_env,
+        source: &source,
+        key: key.as_deref(),
+    };
     let out_file = get_output_file(c.output_file)?;
-    let path = store_env(cached_env, out_file)?;
+    let path = store_env(contents, out_file)?;
     println!("{}", path.display());
     Ok(())
 }
 
-#[cfg(feature = "integration-tests")]
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use std::io::prelude::*;
-
+    #[cfg(feature = "integration-tests")]
     #[test]
     fn output_file_direct() {
         let cfg = OutputFileConfig {
@@ -111,6 +239,7 @@ mod tests {
         assert_direct(cfg);
     }
 
+    #[cfg(feature = "integration-tests")]
     #[test]
     fn output_file_temp() {
         let cfg = OutputFileConfig {
@@ -126,19 +255,85 @@ mod tests {
         assert_temp(cfg);
     }
 
+    #[test]
+    fn output_file_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("link.json");
+        std::os::unix::fs::symlink(dir.path().join("target.json"), &link).unwrap();
+        let cfg = OutputFileConfig {
+            output_file: Some(link),
+            output_dir: None,
+        };
+        assert!(get_output_file(cfg).is_err());
+    }
+
+    #[test]
+    fn stores_private_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("env.json");
+        fs::write(&path, "old").unwrap();
+        let cfg = OutputFileConfig {
+            output_file: Some(path.clone()),
+            output_dir: None,
+        };
+        let contents = Contents {
+            env: env::ProcessEnv::new(env::Secrets::default(), vec![], vec![], false),
+            source: "test",
+            key: None,
+        };
+
+        let stored = store_env(contents, get_output_file(cfg).unwrap()).unwrap();
+
+        assert_eq!(path, stored);
+        assert_ne!("old", fs::read_to_string(&path).unwrap());
+        assert_private_file(&path);
+        assert_only_file(dir.path());
+    }
+
+    #[test]
+    fn writes_private_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, "old").unwrap();
+
+        write_private_file(&path, b"new").unwrap();
+
+        assert_eq!("new", fs::read_to_string(&path).unwrap());
+        assert_private_file(&path);
+        assert_only_file(dir.path());
+
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        assert!(write_private_file(&link, b"other").is_err());
+        assert_eq!("new", fs::read_to_string(&path).unwrap());
+    }
+
+    fn assert_private_file(path: &Path) {
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(FILE_MODE, mode & 0o777);
+    }
+
+    /// No temporary file is left behind next to the written one.
+    fn assert_only_file(dir: &Path) {
+        assert_eq!(1, fs::read_dir(dir).unwrap().count());
+    }
+
+    #[cfg(feature = "integration-tests")]
     fn assert_direct(cfg: OutputFileConfig) {
         let file_name = cfg.output_file.clone().unwrap();
         let f = get_output_file(cfg).unwrap();
         match f {
-            OutputFile::Direct(mut f, _) => {
-                write!(f, "test").unwrap(); // Try write
-                drop(f);
+            OutputFile::Direct(mut f, p) => {
+                write!(f.as_file_mut(), "test").unwrap(); // Try write
+                f.persist(&p).unwrap();
+                assert_private_file(&file_name);
                 fs::remove_file(file_name).unwrap();
             }
             _ => panic!("should return `Direct` case"),
         };
     }
 
+    #[cfg(feature = "integration-tests")]
     fn assert_temp(cfg: OutputFileConfig) {
         let f = get_output_file(cfg).unwrap();
         match f {
@@ -0,0 +1,278 @@
+use anyhow::Result;
+use clap::{ArgGroup, Args, CommandFactory, FromArgMatches, Parser, ValueHint};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::agent::SocketConfig;
+use crate::env::{download_env_with, EnvConfig, ProcessEnv, SecretSelector, Secrets};
+use crate::integrity::IntegrityConfig;
+use crate::run_with::load_env;
+
+#[derive(Error, Debug)]
+pub enum DiffError {
+    #[error("cannot load environment")]
+    Load(#[source] anyhow::Error),
+    #[error("cannot load environment file")]
+    LoadFile(#[source] anyhow::Error),
+    #[error("cannot read the configuration of the environment to compare with")]
+    Config(#[source] anyhow::Error),
+}
+
+/// Compares the variables from the secret store with a cached environment or another secret.
+/// Exits with status 1 if there are differences.
+#[derive(Args, Debug)]
+#[command(group = ArgGroup::new("against").required(true).multiple(false))]
+pub struct Diff {
+    #[command(flatten)]
+    env: EnvConfig,
+
+    /// Path to the environment file created with `cache` command to compare with.
+    #[arg(long, value_parser, value_hint = ValueHint::FilePath, group = "against")]
+    against_env_file: Option<PathBuf>,
+
+    /// The name of another secret in the same secret store to compare with.
+    #[arg(long, group = "against", requires = "cloud")]
+    against_secret_name: Option<String>,
+
+    /// The prefix of other secrets in the same secret store to compare with.
+    #[arg(long, group = "against", requires = "cloud")]
+    against_secret_prefix: Option<String>,
+
+    /// A file with the options of another environment to compare with, e.g. from another account
+    /// or vault. It has an option (with its value) per line, as on the command line.
+    #[arg(long, value_parser, value_hint = ValueHint::FilePath, group = "against")]
+    against_config: Option<PathBuf>,
+
+    /// Print the values of the variables. By default, only the names are printed.
+    #[arg(long)]
+    show_values: bool,
+
+    #[command(flatten)]
+    integrity: IntegrityConfig,
+
+    #[command(flatten)]
+    socket: SocketConfig,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change<'a> {
+    Added(&'a str, &'a str),
+    Removed(&'a str, &'a str),
+    Changed(&'a str, &'a str, &'a str),
+}
+
+fn digest(value: &str) -> [u8; 32] {
+    Sha256::digest(value.as_bytes()).into()
+}
+
+/// Lists the changes needed to get from `old` to `new`, ordered by name. The values are compared
+/// by their hashes.
+pub fn diff<'a>(old: &'a [(String, String)], new: &'a [(String, String)]) -> Vec<Change<'a>> {
+    let old: BTreeMap<_, _> = old.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let new: BTreeMap<_, _> = new.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+    let mut changes = vec![];
+    for (name, value) in &old {
+        match new.get(name) {
+            None => changes.push(Change::Removed(name, value)),
+            Some(new_value) if digest(value) != digest(new_value) => {
+                changes.push(Change::Changed(name, value, new_value))
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, value) in &new {
+        if !old.contains_key(name) {
+            changes.push(Change::Added(name, value));
+        }
+    }
+    changes.sort_by_key(|c| match c {
+        Change::Added(n, _) | Change::Removed(n, _) | Change::Changed(n, _, _) => *n,
+    });
+    changes
+}
+
+pub fn render(changes: &[Change], show_values: bool) -> String {
+    let mut out = String::new();
+    for change in changes {
+        let line = match (change, show_values) {
+            (Change::Added(n, _), false) => format!("+ {n}"),
+            (Change::Removed(n, _), false) => format!("- {n}"),
+            (Change::Changed(n, _, _), false) => format!("~ {n}"),
+            (Change::Added(n, v), true) => format!("+ {n}={v}"),
+            (Change::Removed(n, v), true) => format!("- {n}={v}"),
+            (Change::Changed(n, old, new), true) => format!("~ {n}={old} -> {new}"),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Reads the options of an environment from a file, see `--against-config`. The `KVENV_*`
+/// variables do not apply to them, the variables of the secret stores (e.g. `VAULT_TOKEN`) do.
+fn read_config(path: &Path) -> Result<EnvConfig> {
+    #[derive(Parser)]
+    #[command(no_binary_name = true)]
+    struct Config {
+        #[command(flatten)]
+        env: EnvConfig,
+    }
+
+    let contents = fs::read_to_string(path)?;
+    let args = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .flat_map(|line| match line.split_once(char::is_whitespace) {
+            Some((option, value)) => vec![option, value.trim_start()],
+            None => vec![line],
+        });
+    let mut command = Config::command();
+    let own_vars: Vec<_> = command
+        .get_arguments()
+        .filter(|arg| {
+            arg.get_env()
+                .is_some_and(|var| var.to_string_lossy().starts_with("KVENV_"))
+        })
+        .map(|arg| arg.get_id().clone())
+        .collect();
+    for id in own_vars {
+        command = command.mut_arg(id, |arg| arg.env(None));
+    }
+    let matches = command.try_get_matches_from(args)?;
+    Ok(Config::from_arg_matches(&matches)?.env)
+}
+
+/// Downloads the environment and the variables to compare it with, from secret stores with
+/// `download`.
+fn load(
+    cfg: Diff,
+    download: impl Fn(EnvConfig, Option<SecretSelector>) -> Result<(ProcessEnv, Option<Secrets>)>,
+) -> Result<(Vec<(String, String)>, ProcessEnv)> {
+    let selector = match (cfg.against_secret_name, cfg.against_secret_prefix) {
+        (Some(name), _) => Some(SecretSelector::Name(name)),
+        (_, Some(prefix)) => Some(SecretSelector::Prefix(prefix)),
+        _ => None,
+    };
+    let (env, other) = download(cfg.env, selector).map_err(DiffError::Load)?;
+    let old = match (other, &cfg.against_env_file, &cfg.against_config) {
+        (Some(other), _, _) => other.vars,
+        (None, Some(path), _) => cfg
+            .integrity
+            .key(&cfg.socket)
+            .and_then(|key| load_env(path, key.as_deref()))
+            .map_err(DiffError::LoadFile)?
+            .secrets()
+            .to_vec(),
+        (None, None, Some(path)) => {
+            let against = read_config(path).map_err(DiffError::Config)?;
+            let (against, _) = download(against, None).map_err(DiffError::Load)?;
+            against.secrets().to_vec()
+        }
+        (None, None, None) => unreachable!("clap requires one of the options"),
+    };
+    Ok((old, env))
+}
+
+pub fn run_diff(cfg: Diff) -> Result<std::convert::Infallible> {
+    let show_values = cfg.show_values;
+    let (old, env) = load(cfg, |env, other| download_env_with(env, false, other))?;
+
+    let changes = diff(&old, env.secrets());
+    print!("{}", render(&changes, show_values));
+    std::process::exit(if changes.is_empty() { 0 } else { 1 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! env {
+        ($a:expr, $b:expr) => {
+            ($a.to_string(), $b.to_string())
+        };
+    }
+
+    #[test]
+    fn diffs_environments() {
+        let old = vec![env!("A", "1"), env!("B", "1"), env!("D", "1")];
+        let new = vec![env!("C", "1"), env!("B", "2"), env!("A", "1")];
+
+        let changes = diff(&old, &new);
+
+        assert_eq!(
+            vec![
+                Change::Changed("B", "1", "2"),
+                Change::Added("C", "1"),
+                Change::Removed("D", "1"),
+            ],
+            changes
+        );
+        assert_eq!("~ B\n+ C\n- D\n", render(&changes, false));
+        assert_eq!("~ B=1 -> 2\n+ C=1\n- D=1\n", render(&changes, true));
+        assert!(diff(&old, &old).is_empty());
+    }
+
+    #[cfg(feature = "vault")]
+    #[test]
+    fn diffs_environments_from_two_stores() {
+        use crate::env::{download_env_from, tests::MemoryVault, Vault};
+        use std::{cell::RefCell, io::Write};
+
+        #[derive(Parser)]
+        struct Cli {
+            #[command(flatten)]
+            diff: Diff,
+        }
+
+        let mut config = tempfile::NamedTempFile::new().unwrap();
+        writeln!(
+            config,
+            "# production\n--vault\n--vault-address http://prod:8200"
+        )
+        .unwrap();
+        writeln!(config, "--vault-token t\n--secret-name=app").unwrap();
+        let cfg = Cli::try_parse_from([
+            "diff",
+            "--vault",
+            "--vault-address",
+            "http://staging:8200",
+            "--vault-token",
+            "t",
+            "--secret-name",
+            "app",
+            "--against-config",
+            config.path().to_str().unwrap(),
+        ])
+        .unwrap()
+        .diff;
+        // Popped in the order of the downloads: staging first, then production to compare with
+        let stores = RefCell::new(vec![
+            MemoryVault::new([("app", r#"{"A":"1","B":"1"}"#)]),
+            MemoryVault::new([("app", r#"{"B":"2","C":"1"}"#)]),
+        ]);
+        let download = |env, other| {
+            download_env_from(env, false, other, |_| {
+                let store = stores.borrow_mut().pop().unwrap();
+                Ok(Some(Box::new(store) as Box<dyn Vault>))
+            })
+        };
+
+        let (old, env) = load(cfg, download).unwrap();
+
+        assert!(stores.borrow().is_empty());
+        assert_eq!(
+            vec![
+                Change::Removed("A", "1"),
+                Change::Changed("B", "1", "2"),
+                Change::Added("C", "1"),
+            ],
+            diff(&old, env.secrets())
+        );
+    }
+}
@@ -1,20 +1,26 @@
-use clap::{arg, Args};
+use clap::Args;
 use futures::future::try_join_all;
-use rusoto_core::{request::TlsError, HttpClient, Region};
+use rusoto_core::{request::TlsError, HttpClient, Region, RusotoError};
 use rusoto_credential::{CredentialsError, DefaultCredentialsProvider, StaticProvider};
 use rusoto_secretsmanager::{
+    CreateSecretError, CreateSecretRequest, DeleteSecretError, DeleteSecretRequest,
     GetSecretValueError, GetSecretValueRequest, GetSecretValueResponse, ListSecretsError,
-    ListSecretsRequest, SecretsManager, SecretsManagerClient,
+    ListSecretsRequest, PutSecretValueError, PutSecretValueRequest, SecretsManager,
+    SecretsManagerClient,
 };
-use serde_json::Value;
 use thiserror::Error;
+use tracing::{debug, instrument, warn};
+
+use crate::audit::{self, AuditError};
+use crate::logging;
+use crate::retry::{self, Verdict};
 
 use super::{
-    convert::{convert_env_name, decode_env_from_json},
-    Vault, VaultConfig,
+    convert::{convert_env_name, decode_env_bytes, DecodeOptions},
+    ListedSecret, Secrets, Vault, VaultConfig,
 };
 
-#[derive(Args, Debug)]
+#[derive(Args, Clone, Debug)]
 pub struct AwsConfig {
     /// Use AWS Secrets Manager.
     #[arg(
@@ -58,17 +64,25 @@ pub enum AwsError {
     #[error("rusoto HttpClient error")]
     CredentialsError(#[source] CredentialsError),
     #[error("cannot load secret from Secrets Manager")]
-    GetSecretError(#[source] rusoto_core::RusotoError<GetSecretValueError>),
-    #[error("the secret does not have string data")]
-    NoStringData(String),
+    GetSecretError(#[source] Box<rusoto_core::RusotoError<GetSecretValueError>>),
+    #[error("the secret does not have any data")]
+    NoData(String),
     #[error("the secret name is not valid environemnt variable name")]
     InvalidSecretName(String),
     #[error("cannot list secrets from Secrets Manager")]
-    ListSecretsError(#[source] rusoto_core::RusotoError<ListSecretsError>),
-    #[error("cannot decode secret - it is not a valid JSON object")]
-    DecodeError(#[source] serde_json::Error),
+    ListSecretsError(#[source] Box<rusoto_core::RusotoError<ListSecretsError>>),
     #[error("there are no secrets in the Secrets Manager")]
     NoSecrets,
+    #[error("AWS region is not configured")]
+    NoRegion,
+    #[error("cannot store secret in Secrets Manager")]
+    PutSecretError(#[source] Box<RusotoError<PutSecretValueError>>),
+    #[error("cannot create secret in Secrets Manager")]
+    CreateSecretError(#[source] Box<RusotoError<CreateSecretError>>),
+    #[error("cannot delete secret from Secrets Manager")]
+    DeleteSecretError(#[source] Box<RusotoError<DeleteSecretError>>),
+    #[error(transparent)]
+    AuditError(#[from] AuditError),
 }
 
 pub type Result<T, E = AwsError> = std::result::Result<T, E>;
@@ -85,97 +99,284 @@ impl VaultConfig for AwsConfig {
     }
 
     fn into_vault(self) -> anyhow::Result<Self::Vault> {
+        Ok(self.build()?)
+    }
+
+    fn ref_vault(&self, _location: Option<&str>) -> anyhow::Result<Self::Vault> {
+        Ok(self.build()?)
+    }
+
+    fn credential_vars(&self) -> &'static [&'static str] {
+        &[
+            "AWS_ACCESS_KEY_ID",
+            "AWS_SECRET_ACCESS_KEY",
+            "AWS_SESSION_TOKEN",
+        ]
+    }
+}
+
+impl AwsConfig {
+    #[instrument(name = "credential", level = "debug", skip_all, fields(region = ?self.aws_region))]
+    fn build(&self) -> Result<AwsVault> {
         let http_client = HttpClient::new().map_err(AwsError::TlsError)?;
-        if let Some(key_id) = self.aws_access_key_id {
-            let secret = self.aws_secret_access_key.unwrap();
-            let provider = StaticProvider::new_minimal(key_id, secret);
-            Ok(Self::Vault {
-                client: SecretsManagerClient::new_with(
-                    http_client,
-                    provider,
-                    self.aws_region.unwrap(),
-                ),
+        let region = self.aws_region.clone().ok_or(AwsError::NoRegion)?;
+        if let Some(key_id) = &self.aws_access_key_id {
+            debug!(key_id, "using the access key");
+            let secret = self.aws_secret_access_key.clone().unwrap();
+            let provider = StaticProvider::new_minimal(key_id.clone(), secret);
+            Ok(AwsVault {
+                client: SecretsManagerClient::new_with(http_client, provider, region),
             })
         } else {
-            let provider = DefaultCredentialsProvider::new().map_err(AwsError::CredentialsError)?;
-            Ok(Self::Vault {
-                client: SecretsManagerClient::new_with(
-                    http_client,
-                    provider,
-                    self.aws_region.unwrap(),
-                ),
+            debug!("using the default credential chain");
+            let provider = DefaultCredentialsProvider::new()
+                .map_err(AwsError::CredentialsError)
+                .inspect_err(|e| {
+                    warn!(
+                        error = e as &dyn std::error::Error,
+                        "cannot set up the credentials"
+                    )
+                })?;
+            Ok(AwsVault {
+                client: SecretsManagerClient::new_with(http_client, provider, region),
             })
         }
     }
 }
 
-impl Vault for AwsVault {
-    #[tokio::main]
-    async fn download_prefixed(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let list = self
-            .client
-            .list_secrets(ListSecretsRequest {
+impl AwsVault {
+    /// Reads the secret (in the latest version if not specified) and records it in the audit log.
+    #[instrument(level = "debug", skip(self))]
+    async fn get_secret(&self, name: &str, version: Option<&str>) -> Result<SecretData> {
+        let request = || {
+            self.client.get_secret_value(GetSecretValueRequest {
+                secret_id: name.to_string(),
+                version_id: version.map(str::to_string),
+                version_stage: None,
+            })
+        };
+        let internal = |e: &_| matches!(e, GetSecretValueError::InternalServiceError(_));
+        let secret = retry::retry(request, |e| verdict(e, internal))
+            .await
+            .map_err(|e| AwsError::GetSecretError(Box::new(e)))
+            .inspect_err(|e| {
+                warn!(
+                    secret = name,
+                    error = e as &dyn std::error::Error,
+                    "cannot read the secret"
+                )
+            })?;
+        debug!(version = secret.version_id, "read the secret");
+        audit::secret_read("aws", None, name, secret.version_id.as_deref())?;
+        decode_secret(name, secret)
+    }
+
+    /// Names of the secrets starting with the prefix, in alphabetical order.
+    #[instrument(level = "debug", skip(self))]
+    async fn list_names(&self, prefix: &str) -> Result<Vec<String>> {
+        let request = || {
+            self.client.list_secrets(ListSecretsRequest {
                 max_results: Some(100),
                 ..Default::default()
             })
+        };
+        let internal = |e: &_| matches!(e, ListSecretsError::InternalServiceError(_));
+        let list = retry::retry(request, |e| verdict(e, internal))
             .await
-            .map_err(AwsError::ListSecretsError)?;
-        let results = list
+            .map_err(|e| AwsError::ListSecretsError(Box::new(e)))
+            .inspect_err(|e| {
+                warn!(
+                    prefix,
+                    error = e as &dyn std::error::Error,
+                    "cannot list the secrets"
+                )
+            })?;
+        let mut names: Vec<_> = list
             .secret_list
             .ok_or(AwsError::NoSecrets)?
             .into_iter()
-            .filter(|x| {
-                x.name
-                    .as_ref()
-                    .map(|n| n.starts_with(prefix))
-                    .unwrap_or(false)
-            })
-            .map(|s| async {
-                let name = s.name.unwrap();
-                let secret = self
-                    .client
-                    .get_secret_value(GetSecretValueRequest {
-                        secret_id: name.clone(),
-                        version_id: None,
-                        version_stage: None,
-                    })
-                    .await
-                    .map_err(AwsError::GetSecretError)?;
-                let value = secret
-                    .secret_string
-                    .ok_or_else(|| AwsError::NoStringData(name.clone()))?;
-                let name = convert_env_name(prefix, &name)
-                    .map_err(|_| AwsError::InvalidSecretName(name.clone()))?;
-                Ok::<_, AwsError>((name, value))
-            });
-        let values: Vec<_> = try_join_all(results).await?.into_iter().collect();
-        Ok(values)
+            .filter_map(|x| x.name)
+            .filter(|n| n.starts_with(prefix))
+            .collect();
+        names.sort();
+        debug!(count = names.len(), "listed the secrets");
+        Ok(names)
     }
+}
 
+impl Vault for AwsVault {
+    #[instrument(skip(self, _opts))]
     #[tokio::main]
-    async fn download_json(&self, secret_name: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let secret = self
-            .client
-            .get_secret_value(GetSecretValueRequest {
-                secret_id: secret_name.to_string(),
-                version_id: None,
-                version_stage: None,
-            })
-            .await
-            .map_err(AwsError::GetSecretError)?;
-        let value = decode_secret(secret)?;
-        decode_env_from_json(secret_name, value)
+    async fn download_prefixed(
+        &self,
+        prefix: &str,
+        _opts: &DecodeOptions,
+    ) -> anyhow::Result<Secrets> {
+        retry::timeout(async {
+            let results = self
+                .list_names(prefix)
+                .await?
+                .into_iter()
+                .map(|name| async move {
+                    let value = self.get_secret(&name, None).await?;
+                    let name = convert_env_name(prefix, &name)
+                        .map_err(|_| AwsError::InvalidSecretName(name.clone()))?;
+                    Ok::<_, AwsError>((name, value))
+                });
+            let mut secrets = Secrets::default();
+            for (name, value) in try_join_all(results).await? {
+                match value {
+                    SecretData::Text(s) => secrets.push_text(name, s),
+                    SecretData::Binary(b) => secrets.push_binary(name, &b),
+                }
+            }
+            Ok(secrets)
+        })
+        .await
     }
+
+    #[instrument(skip(self, _opts))]
+    #[tokio::main]
+    async fn list_prefixed(
+        &self,
+        prefix: &str,
+        _opts: &DecodeOptions,
+    ) -> anyhow::Result<Vec<ListedSecret>> {
+        retry::timeout(async {
+            let names = self.list_names(prefix).await?;
+            Ok(names
+                .into_iter()
+                .map(|name| {
+                    let vars = convert_env_name(prefix, &name).map(|n| vec![n]);
+                    ListedSecret::new(name, vars)
+                })
+                .collect())
+        })
+        .await
+    }
+
+    #[instrument(skip(self, opts))]
+    #[tokio::main]
+    async fn download_json(
+        &self,
+        secret_name: &str,
+        opts: &DecodeOptions,
+    ) -> anyhow::Result<Secrets> {
+        retry::timeout(async {
+            let value = match self.get_secret(secret_name, None).await? {
+                SecretData::Text(s) => s.into_bytes(),
+                SecretData::Binary(b) => b,
+            };
+            decode_env_bytes(secret_name, value, opts)
+        })
+        .await
+    }
+
+    #[instrument(skip(self))]
+    #[tokio::main]
+    async fn download_raw(
+        &self,
+        secret_name: &str,
+        version: Option<&str>,
+    ) -> anyhow::Result<Vec<u8>> {
+        retry::timeout(async {
+            match self.get_secret(secret_name, version).await? {
+                SecretData::Text(s) => Ok(s.into_bytes()),
+                SecretData::Binary(b) => Ok(b),
+            }
+        })
+        .await
+    }
+
+    #[tokio::main]
+    async fn upload_raw(&self, secret_name: &str, value: &str) -> anyhow::Result<()> {
+        retry::timeout(async {
+            let put = || {
+                self.client.put_secret_value(PutSecretValueRequest {
+                    secret_id: secret_name.to_string(),
+                    secret_string: Some(value.to_string()),
+                    ..Default::default()
+                })
+            };
+            let internal = |e: &_| matches!(e, PutSecretValueError::InternalServiceError(_));
+            match retry::retry(put, |e| verdict(e, internal)).await {
+                Ok(_) => Ok(()),
+                Err(RusotoError::Service(PutSecretValueError::ResourceNotFound(_))) => {
+                    let create = || {
+                        self.client.create_secret(CreateSecretRequest {
+                            name: secret_name.to_string(),
+                            secret_string: Some(value.to_string()),
+                            ..Default::default()
+                        })
+                    };
+                    let internal = |e: &_| matches!(e, CreateSecretError::InternalServiceError(_));
+                    retry::retry(create, |e| verdict(e, internal))
+                        .await
+                        .map_err(|e| AwsError::CreateSecretError(Box::new(e)))?;
+                    Ok(())
+                }
+                Err(e) => Err(AwsError::PutSecretError(Box::new(e)).into()),
+            }
+        })
+        .await
+    }
+
+    #[tokio::main]
+    async fn delete_secret(&self, secret_name: &str) -> anyhow::Result<()> {
+        retry::timeout(async {
+            // The secret is kept for the default recovery window of 30 days
+            let request = || {
+                self.client.delete_secret(DeleteSecretRequest {
+                    secret_id: secret_name.to_string(),
+                    ..Default::default()
+                })
+            };
+            let internal = |e: &_| matches!(e, DeleteSecretError::InternalServiceError(_));
+            retry::retry(request, |e| verdict(e, internal))
+                .await
+                .map_err(|e| AwsError::DeleteSecretError(Box::new(e)))?;
+            Ok(())
+        })
+        .await
+    }
+}
+
+enum SecretData {
+    Text(String),
+    Binary(Vec<u8>),
 }
 
-fn decode_secret(secret: GetSecretValueResponse) -> Result<Value> {
-    secret
-        .secret_string
-        .as_ref()
-        .map(|x| serde_json::from_str(&x[..]))
-        .or_else(|| secret.secret_binary.map(|b| serde_json::from_slice(&b)))
-        .unwrap()
-        .map_err(AwsError::DecodeError)
+/// Throttled requests (reported as unknown errors), server errors and failed connections are
+/// retried.
+fn verdict<E>(error: &RusotoError<E>, internal: impl Fn(&E) -> bool) -> Verdict {
+    match error {
+        RusotoError::HttpDispatch(_) => Verdict::Retry(None),
+        RusotoError::Service(e) if internal(e) => Verdict::Retry(None),
+        RusotoError::Unknown(response) => {
+            let retry_after = response
+                .headers
+                .get("retry-after")
+                .and_then(|v| retry::parse_retry_after(v));
+            if response.body_as_str().contains("ThrottlingException") {
+                Verdict::Retry(retry_after)
+            } else {
+                retry::status_verdict(response.status.as_u16(), retry_after)
+            }
+        }
+        _ => Verdict::Fail,
+    }
+}
+
+fn decode_secret(name: &str, secret: GetSecretValueResponse) -> Result<SecretData> {
+    if let Some(s) = secret.secret_string {
+        logging::redact_value(s.as_bytes());
+        Ok(SecretData::Text(s))
+    } else if let Some(b) = secret.secret_binary {
+        logging::redact_value(&b);
+        Ok(SecretData::Binary(b.to_vec()))
+    } else {
+        Err(AwsError::NoData(name.to_string()))
+    }
 }
 
 #[cfg(test)]
@@ -206,8 +407,9 @@ mod tests {
         let proc_env = cfg
             .into_vault()
             .unwrap()
-            .download_json("kvenv-tests/single")
-            .unwrap();
+            .download_json("kvenv-tests/single", &DecodeOptions::default())
+            .unwrap()
+            .vars;
         assert_eq!(
             vec![
                 env!("INTEGRATION_TESTS_A", "work1"),
@@ -230,8 +432,9 @@ mod tests {
         let proc_env = cfg
             .into_vault()
             .unwrap()
-            .download_prefixed("kvenv-tests/prefixed-")
-            .unwrap();
+            .download_prefixed("kvenv-tests/prefixed-", &DecodeOptions::default())
+            .unwrap()
+            .vars;
         assert_eq!(
             vec![
                 env!("INTEGRATION_TESTS_A", "work1"),
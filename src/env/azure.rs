@@ -1,22 +1,35 @@
 use std::sync::Arc;
 
-use azure_core::auth::TokenCredential;
+use azure_core::{
+    auth::TokenCredential,
+    error::{ErrorKind, HttpError},
+    headers::{AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, MS_DATE, RETRY_AFTER, USER_AGENT},
+    CollectedResponse, HttpClient, Method, Request, Response, StatusCode, Url, EMPTY_BODY,
+};
 use azure_identity::{
     ClientSecretCredential, DefaultAzureCredentialBuilder, TokenCredentialOptions,
 };
-use azure_security_keyvault::prelude::*;
-use clap::{arg, command, ArgGroup, Args};
+use azure_security_keyvault::{
+    prelude::{KeyVaultGetSecretResponse, KeyVaultGetSecretsResponse},
+    API_VERSION,
+};
+use clap::{ArgGroup, Args};
 use futures::future::try_join_all;
-use futures::stream::StreamExt;
-use serde_json::Value;
+use serde_json::json;
+use std::time::{Duration, SystemTime};
 use thiserror::Error;
+use tracing::{debug, instrument, warn};
+
+use crate::audit::{self, AuditError};
+use crate::logging;
+use crate::retry::{self, Verdict};
 
 use super::{
-    convert::{convert_env_name, decode_env_from_json},
-    Vault, VaultConfig,
+    convert::{convert_env_name, decode_env, DecodeOptions},
+    ListedSecret, Secrets, Vault, VaultConfig,
 };
 
-#[derive(Args, Debug)]
+#[derive(Args, Clone, Debug)]
 #[command(group = ArgGroup::new("keyvault"))]
 pub struct AzureConfig {
     /// Use Azure Key Vault.
@@ -53,7 +66,7 @@ pub struct AzureConfig {
     azure_keyvault_url: Option<String>,
 }
 
-#[derive(Args, Debug, Default)]
+#[derive(Args, Clone, Debug, Default)]
 pub struct AzureCredential {
     /// [Azure] The tenant id of the service principal used for authorization.
     #[arg(long, env = "AZURE_TENANT_ID", display_order = 203)]
@@ -80,14 +93,33 @@ pub enum AzureError {
     #[error("cannot create Azure KeyVault client")]
     ClientError(#[source] azure_core::Error),
     #[error("cannot download secret")]
-    CannotDownloadSecrets(#[source] azure_core::Error),
-    #[error("cannot decode secret - it is not a valid JSON")]
-    DecodeError(#[source] serde_json::Error),
+    CannotDownloadSecrets(#[source] RequestError),
+    #[error("cannot store secret")]
+    CannotUploadSecret(#[source] RequestError),
+    #[error("cannot delete secret")]
+    CannotDeleteSecret(#[source] RequestError),
+    #[error(transparent)]
+    CannotAudit(#[from] AuditError),
+}
+
+/// A failed request to Key Vault.
+#[derive(Error, Debug)]
+pub enum RequestError {
+    #[error(transparent)]
+    Azure(#[from] azure_core::Error),
+    #[error("Key Vault returned {0}")]
+    HttpStatusCodeError(StatusCode, Option<Duration>, #[source] Box<HttpError>),
+    #[error("cannot parse the response of Key Vault")]
+    DeserializeError(#[from] serde_json::Error),
 }
 
 pub struct AzureVault {
     kv_address: String,
+    url: Url,
+    /// The resource of the access tokens, e.g. `https://vault.azure.net`.
+    scope: String,
     credential: Arc<dyn TokenCredential>,
+    http_client: Arc<dyn HttpClient>,
 }
 
 pub type Result<T, E = AzureError> = std::result::Result<T, E>;
@@ -112,9 +144,15 @@ impl AzureCredential {
         }
     }
 
+    #[instrument(name = "credential", level = "debug", skip_all)]
     fn to_credential(&self) -> Result<Arc<dyn TokenCredential>> {
         self.validate()?;
         if self.is_valid() {
+            debug!(
+                tenant = self.azure_tenant_id,
+                client = self.azure_client_id,
+                "using the service principal"
+            );
             let creds = ClientSecretCredential::new(
                 azure_core::new_http_client(),
                 self.azure_tenant_id.clone().unwrap(),
@@ -124,6 +162,7 @@ impl AzureCredential {
             );
             Ok(Arc::new(creds))
         } else {
+            debug!("using the default credential chain");
             let creds = DefaultAzureCredentialBuilder::new()
                 .exclude_environment_credential()
                 .build();
@@ -156,73 +195,283 @@ impl VaultConfig for AzureConfig {
     fn into_vault(self) -> anyhow::Result<Self::Vault> {
         let kv_address = self.get_kv_address()?;
         let credential = self.credential.to_credential()?;
+        Ok(AzureVault::new(kv_address, credential)?)
+    }
+
+    fn ref_vault(&self, location: Option<&str>) -> anyhow::Result<Self::Vault> {
+        let kv_address = match location {
+            Some(name) => format!("https://{name}.vault.azure.net"),
+            None => self.get_kv_address()?,
+        };
+        let credential = self.credential.to_credential()?;
+        Ok(AzureVault::new(kv_address, credential)?)
+    }
+
+    fn credential_vars(&self) -> &'static [&'static str] {
+        &["AZURE_TENANT_ID", "AZURE_CLIENT_ID", "AZURE_CLIENT_SECRET"]
+    }
+}
+
+impl AzureVault {
+    fn new(kv_address: String, credential: Arc<dyn TokenCredential>) -> Result<Self> {
+        let url = Url::parse(&kv_address).map_err(|e| AzureError::ClientError(e.into()))?;
+        let host = url.host_str().unwrap_or_default();
+        let scope = format!(
+            "{}://{}",
+            url.scheme(),
+            host.split_once('.').map_or(host, |(_, domain)| domain)
+        );
+        // The client of `azure_security_keyvault` always retries with the default policy of
+        // `azure_core`, on top of `retry::retry`. The requests are sent with the HTTP client
+        // directly, which also keeps the headers of failed responses.
         Ok(AzureVault {
             kv_address,
+            url,
+            scope,
             credential,
+            http_client: azure_core::new_http_client(),
         })
     }
-}
 
-impl AzureVault {
-    fn get_client(&self) -> Result<SecretClient> {
-        SecretClient::new(&self.kv_address, self.credential.clone())
-            .map_err(AzureError::ClientError)
+    /// The URL of the Key Vault API `path`, e.g. `secrets/name`.
+    fn url(&self, path: &str) -> Url {
+        let mut url = self.url.clone();
+        url.set_path(path);
+        url.set_query(Some(&format!("api-version={API_VERSION}")));
+        url
+    }
+
+    /// Sends a single request with a new access token.
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        body: Option<serde_json::Value>,
+    ) -> Result<CollectedResponse, RequestError> {
+        let token = self.credential.get_token(&self.scope).await?;
+        let mut request = Request::new(url, method);
+        request.insert_header(AUTHORIZATION, format!("Bearer {}", token.token.secret()));
+        request.insert_header(MS_DATE, httpdate::fmt_http_date(SystemTime::now()));
+        request.insert_header(
+            USER_AGENT,
+            concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")),
+        );
+        match body {
+            Some(body) => {
+                let body = body.to_string();
+                request.insert_header(CONTENT_TYPE, "application/json");
+                request.insert_header(CONTENT_LENGTH, body.len().to_string());
+                request.set_body(body);
+            }
+            None => {
+                request.insert_header(CONTENT_LENGTH, "0");
+                request.set_body(EMPTY_BODY);
+            }
+        }
+        let response = self.http_client.execute_request(&request).await?;
+        if !response.status().is_success() {
+            return Err(status_error(response).await);
+        }
+        Ok(CollectedResponse::from_response(response).await?)
     }
 
     fn strip_prefix(name: &str) -> &str {
         let idx = name.rfind('/').unwrap();
         &name[(idx + 1)..]
     }
-}
 
-impl Vault for AzureVault {
-    #[tokio::main]
-    async fn download_prefixed(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let client = self.get_client()?;
+    /// Reads the secret (in the latest version if not specified) and records it in the audit log.
+    #[instrument(level = "debug", skip(self))]
+    async fn get_secret(&self, name: &str, version: Option<&str>) -> Result<String> {
+        let url = self.url(&format!("secrets/{name}/{}", version.unwrap_or_default()));
+        let request = || async {
+            let response = self.send(Method::Get, url.clone(), None).await?;
+            Ok(serde_json::from_slice::<KeyVaultGetSecretResponse>(
+                response.body(),
+            )?)
+        };
+        let secret = retry::retry(request, verdict)
+            .await
+            .map_err(AzureError::CannotDownloadSecrets)
+            .inspect_err(|e| {
+                warn!(
+                    secret = name,
+                    error = e as &dyn std::error::Error,
+                    "cannot read the secret"
+                )
+            })?;
+        // The id ends with the version, e.g. `https://kv.vault.azure.net/secrets/name/version`
+        let version = AzureVault::strip_prefix(&secret.id);
+        debug!(version, "read the secret");
+        audit::secret_read("azure", Some(&self.kv_address), name, Some(version))?;
+        logging::redact_value(secret.value.as_bytes());
+        Ok(secret.value)
+    }
 
-        let secrets = client
-            .list_secrets()
-            .into_stream()
-            .collect::<Vec<_>>()
+    /// Names of the secrets starting with the prefix, in alphabetical order.
+    #[instrument(level = "debug", skip(self), fields(address = self.kv_address))]
+    async fn list_names(&self, prefix: &str) -> Result<Vec<String>> {
+        let request = || async {
+            let mut pages = vec![];
+            let mut url = Some(self.url("secrets"));
+            while let Some(page) = url {
+                let response = self.send(Method::Get, page, None).await?;
+                let page: KeyVaultGetSecretsResponse = serde_json::from_slice(response.body())?;
+                url = page
+                    .next_link
+                    .as_deref()
+                    .map(Url::parse)
+                    .transpose()
+                    .map_err(azure_core::Error::from)?;
+                pages.push(page);
+            }
+            Ok::<_, RequestError>(pages)
+        };
+        let secrets = retry::retry(request, verdict)
             .await
-            .into_iter()
-            .collect::<Result<Vec<_>, _>>()
-            .map_err(AzureError::CannotDownloadSecrets)?;
-        let secrets: Vec<_> = secrets
+            .map_err(AzureError::CannotDownloadSecrets)
+            .inspect_err(|e| {
+                warn!(
+                    prefix,
+                    error = e as &dyn std::error::Error,
+                    "cannot list the secrets"
+                )
+            })?;
+        let mut names: Vec<_> = secrets
             .into_iter()
             .flat_map(|x| x.value.into_iter().map(|x| x.id))
             .map(|x| AzureVault::strip_prefix(&x).to_string())
             .filter(|x| x.starts_with(prefix))
             .collect();
-        let env_names = secrets
-            .iter()
-            .map(|x| convert_env_name(prefix, x))
-            .collect::<anyhow::Result<Vec<_>>>()?;
-        let env_values = secrets.iter().map(|s| {
-            let client = self.get_client();
-            async move {
-                client?
-                    .get(s)
-                    .into_future()
-                    .await
-                    .map_err(AzureError::CannotDownloadSecrets)
-            }
-        });
-        let env_values = try_join_all(env_values).await?.into_iter().map(|x| x.value);
-        let from_kv = env_names.into_iter().zip(env_values.into_iter()).collect();
-        Ok(from_kv)
+        names.sort();
+        debug!(count = names.len(), "listed the secrets");
+        Ok(names)
+    }
+}
+
+impl Vault for AzureVault {
+    #[instrument(skip(self, _opts), fields(address = self.kv_address))]
+    #[tokio::main]
+    async fn download_prefixed(
+        &self,
+        prefix: &str,
+        _opts: &DecodeOptions,
+    ) -> anyhow::Result<Secrets> {
+        retry::timeout(async {
+            let secrets = self.list_names(prefix).await?;
+            let env_names = secrets
+                .iter()
+                .map(|x| convert_env_name(prefix, x))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let env_values = secrets.iter().map(|s| self.get_secret(s, None));
+            let env_values = try_join_all(env_values).await?;
+            let from_kv: Vec<_> = env_names.into_iter().zip(env_values.into_iter()).collect();
+            Ok(from_kv.into())
+        })
+        .await
+    }
+
+    #[instrument(skip(self, _opts), fields(address = self.kv_address))]
+    #[tokio::main]
+    async fn list_prefixed(
+        &self,
+        prefix: &str,
+        _opts: &DecodeOptions,
+    ) -> anyhow::Result<Vec<ListedSecret>> {
+        retry::timeout(async {
+            let names = self.list_names(prefix).await?;
+            Ok(names
+                .into_iter()
+                .map(|name| {
+                    let vars = convert_env_name(prefix, &name).map(|n| vec![n]);
+                    ListedSecret::new(name, vars)
+                })
+                .collect())
+        })
+        .await
     }
 
+    #[instrument(skip(self, opts), fields(address = self.kv_address))]
     #[tokio::main]
-    async fn download_json(&self, secret_name: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let client = self.get_client()?;
-        let secret = client
-            .get(secret_name)
-            .into_future()
+    async fn download_json(
+        &self,
+        secret_name: &str,
+        opts: &DecodeOptions,
+    ) -> anyhow::Result<Secrets> {
+        retry::timeout(async {
+            let secret = self.get_secret(secret_name, None).await?;
+            Ok(decode_env(secret_name, &secret, opts)?.into())
+        })
+        .await
+    }
+
+    #[instrument(skip(self), fields(address = self.kv_address))]
+    #[tokio::main]
+    async fn download_raw(
+        &self,
+        secret_name: &str,
+        version: Option<&str>,
+    ) -> anyhow::Result<Vec<u8>> {
+        retry::timeout(async { Ok(self.get_secret(secret_name, version).await?.into_bytes()) })
             .await
-            .map_err(AzureError::CannotDownloadSecrets)?;
-        let value: Value = serde_json::from_str(&secret.value).map_err(AzureError::DecodeError)?;
-        decode_env_from_json(secret_name, value)
+    }
+
+    #[tokio::main]
+    async fn upload_raw(&self, secret_name: &str, value: &str) -> anyhow::Result<()> {
+        let url = self.url(&format!("secrets/{secret_name}"));
+        let body = json!({ "value": value });
+        let request = || self.send(Method::Put, url.clone(), Some(body.clone()));
+        retry::timeout(async {
+            retry::retry(request, verdict)
+                .await
+                .map_err(AzureError::CannotUploadSecret)?;
+            Ok(())
+        })
+        .await
+    }
+
+    #[tokio::main]
+    async fn delete_secret(&self, secret_name: &str) -> anyhow::Result<()> {
+        let url = self.url(&format!("secrets/{secret_name}"));
+        let request = || self.send(Method::Delete, url.clone(), None);
+        retry::timeout(async {
+            retry::retry(request, verdict)
+                .await
+                .map_err(AzureError::CannotDeleteSecret)?;
+            Ok(())
+        })
+        .await
+    }
+
+    fn prefixed_secret(&self, prefix: &str, name: &str, value: &str) -> (String, String) {
+        // Key Vault does not allow `_` in names, `download_prefixed` maps `-` back to `_`
+        let name = format!("{prefix}{}", name.replace('_', "-"));
+        (name, value.to_string())
+    }
+}
+
+/// The error of a failed response, with the delay requested by its `Retry-After` header.
+async fn status_error(response: Response) -> RequestError {
+    let status = response.status();
+    let retry_after = response
+        .headers()
+        .get_optional_str(&RETRY_AFTER)
+        .and_then(retry::parse_retry_after);
+    RequestError::HttpStatusCodeError(
+        status,
+        retry_after,
+        Box::new(HttpError::new(response).await),
+    )
+}
+
+/// Throttled requests, server errors and failed connections are retried.
+fn verdict(error: &RequestError) -> Verdict {
+    match error {
+        RequestError::HttpStatusCodeError(status, retry_after, _) => {
+            retry::status_verdict(u16::from(*status), *retry_after)
+        }
+        RequestError::Azure(e) if matches!(e.kind(), ErrorKind::Io) => Verdict::Retry(None),
+        _ => Verdict::Fail,
     }
 }
 
@@ -267,6 +516,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn retries_after_the_requested_delay() {
+        use azure_core::headers::Headers;
+
+        let error = |status, retry_after: Option<&str>| {
+            let mut headers = Headers::new();
+            if let Some(retry_after) = retry_after {
+                headers.insert("retry-after", retry_after.to_string());
+            }
+            let body = futures::stream::iter([Ok(EMPTY_BODY)]);
+            let response = Response::new(status, headers, Box::pin(body));
+            futures::executor::block_on(status_error(response))
+        };
+
+        assert_eq!(
+            Verdict::Retry(Some(Duration::from_secs(7))),
+            verdict(&error(StatusCode::TooManyRequests, Some("7")))
+        );
+        assert_eq!(
+            Verdict::Retry(None),
+            verdict(&error(StatusCode::ServiceUnavailable, None))
+        );
+        assert_eq!(
+            Verdict::Fail,
+            verdict(&error(StatusCode::NotFound, Some("7")))
+        );
+    }
+
     #[cfg(feature = "integration-tests")]
     #[test]
     fn integration_tests_single_value() {
@@ -284,8 +561,9 @@ mod tests {
         let proc_env = cfg
             .into_vault()
             .unwrap()
-            .download_json("integ-tests")
-            .unwrap();
+            .download_json("integ-tests", &DecodeOptions::default())
+            .unwrap()
+            .vars;
         assert_eq!(vec![env!("INTEGRATION_TESTS", "work")], proc_env);
     }
 
@@ -306,8 +584,9 @@ mod tests {
         let proc_env = cfg
             .into_vault()
             .unwrap()
-            .download_prefixed("prefixed-")
-            .unwrap();
+            .download_prefixed("prefixed-", &DecodeOptions::default())
+            .unwrap()
+            .vars;
         assert_eq!(
             vec![
                 env!("INTEGRATION_TESTS_A", "work1"),
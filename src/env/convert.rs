@@ -1,14 +1,62 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
 use serde_json::Value;
+use std::collections::HashSet;
+
+use super::Secrets;
+
+/// Describes what happens with nested objects and arrays in JSON secrets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum NestedValues {
+    /// Nested values are not allowed and the secret is rejected.
+    #[default]
+    Reject,
+    /// Nested values are flattened into separate variables, e.g. `PARENT__CHILD` or `PARENT__0`.
+    Flatten,
+    /// Nested values are stored as JSON strings.
+    Stringify,
+}
+
+/// The format of the single secret with the environment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SecretFormat {
+    /// A JSON object.
+    #[default]
+    Json,
+    /// A `.env` file with `KEY=VALUE` pairs.
+    Dotenv,
+    /// A YAML mapping.
+    Yaml,
+    /// A TOML document.
+    Toml,
+    /// A single variable named after the secret, with the contents of the secret as the value.
+    Raw,
+    /// Detect the format from the contents (JSON, TOML, YAML and then dotenv).
+    Auto,
+}
+
+#[derive(Clone, Debug)]
+pub struct DecodeOptions {
+    pub format: SecretFormat,
+    pub nested: NestedValues,
+    pub separator: String,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            format: SecretFormat::Json,
+            nested: NestedValues::Reject,
+            separator: "__".to_string(),
+        }
+    }
+}
 
 pub fn as_valid_env_name(name: String) -> Result<String> {
     let is_valid = |c: char| c.is_ascii_alphanumeric() || c == '_';
     if !name.is_empty()
         && name.chars().all(is_valid)
-        && name
-            .chars()
-            .next()
-            .map_or(false, |c| c.is_ascii_alphabetic())
+        && name.chars().next().is_some_and(|c| c.is_ascii_alphabetic())
     {
         Ok(name)
     } else {
@@ -33,13 +81,72 @@ pub fn convert_env_name(prefix: &str, name: &str) -> Result<String> {
     as_valid_env_name(name)
 }
 
-#[allow(dead_code)]
-pub fn decode_env_from_json(name: &str, value: Value) -> Result<Vec<(String, String)>> {
-    match value {
-        Value::Object(m) => m
+fn flatten_value(
+    name: &str,
+    key: String,
+    value: Value,
+    opts: &DecodeOptions,
+    out: &mut Vec<(String, String)>,
+) -> Result<()> {
+    let children: Vec<(String, Value)> = match value {
+        Value::Object(m) => m.into_iter().collect(),
+        Value::Array(a) => a
             .into_iter()
-            .map(|(k, v)| Ok((as_valid_env_name(k)?, value_as_string(name, v)?)))
+            .enumerate()
+            .map(|(i, v)| (i.to_string(), v))
             .collect(),
+        v => {
+            out.push((as_valid_env_name(key)?, value_as_string(name, v)?));
+            return Ok(());
+        }
+    };
+    for (k, v) in children {
+        flatten_value(name, format!("{key}{}{k}", opts.separator), v, opts, out)?;
+    }
+    Ok(())
+}
+
+fn decode_value(
+    name: &str,
+    key: String,
+    value: Value,
+    opts: &DecodeOptions,
+    out: &mut Vec<(String, String)>,
+) -> Result<()> {
+    match (opts.nested, value) {
+        (NestedValues::Flatten, v @ (Value::Object(_) | Value::Array(_))) => {
+            flatten_value(name, key, v, opts, out)
+        }
+        (NestedValues::Stringify, v @ (Value::Object(_) | Value::Array(_))) => {
+            out.push((as_valid_env_name(key)?, v.to_string()));
+            Ok(())
+        }
+        (_, v) => {
+            out.push((as_valid_env_name(key)?, value_as_string(name, v)?));
+            Ok(())
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub fn decode_env_from_json(
+    name: &str,
+    value: Value,
+    opts: &DecodeOptions,
+) -> Result<Vec<(String, String)>> {
+    match value {
+        Value::Object(m) => {
+            let mut out = Vec::with_capacity(m.len());
+            for (k, v) in m {
+                decode_value(name, k, v, opts, &mut out)?;
+            }
+            // Flattened keys may clash with each other or with top-level keys
+            let mut keys = HashSet::with_capacity(out.len());
+            if let Some((key, _)) = out.iter().find(|(k, _)| !keys.insert(k)) {
+                bail!("variable '{key}' of secret '{name}' is defined more than once");
+            }
+            Ok(out)
+        }
         _ => bail!(
             "top-level value for secret '{}' must be a JSON object",
             name
@@ -47,6 +154,141 @@ pub fn decode_env_from_json(name: &str, value: Value) -> Result<Vec<(String, Str
     }
 }
 
+fn unquote_dotenv_value(value: &str) -> Option<String> {
+    if let Some(rest) = value.strip_prefix('"') {
+        let mut result = String::with_capacity(rest.len());
+        let mut chars = rest.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => {
+                    let tail = chars.as_str().trim_start();
+                    return (tail.is_empty() || tail.starts_with('#')).then_some(result);
+                }
+                '\\' => match chars.next()? {
+                    'n' => result.push('\n'),
+                    'r' => result.push('\r'),
+                    't' => result.push('\t'),
+                    c => result.push(c),
+                },
+                c => result.push(c),
+            }
+        }
+        None
+    } else if let Some(rest) = value.strip_prefix('\'') {
+        let (result, tail) = rest.split_once('\'')?;
+        let tail = tail.trim_start();
+        (tail.is_empty() || tail.starts_with('#')).then(|| result.to_string())
+    } else {
+        let value = value.find(" #").map_or(value, |idx| &value[..idx]);
+        Some(value.trim_end().to_string())
+    }
+}
+
+pub fn decode_env_from_dotenv(name: &str, raw: &str) -> Result<Vec<(String, String)>> {
+    raw.lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_no, line)| {
+            let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                anyhow!("line {line_no} of secret '{name}' is not a `KEY=VALUE` pair")
+            })?;
+            let value = unquote_dotenv_value(value.trim()).ok_or_else(|| {
+                anyhow!("line {line_no} of secret '{name}' has an invalid quoted value")
+            })?;
+            Ok((as_valid_env_name(key.trim_end().to_string())?, value))
+        })
+        .collect()
+}
+
+/// Encodes the variables as a `.env` file with double-quoted values, which both
+/// `decode_env_from_dotenv` and the format detection read back as written.
+pub fn encode_env_to_dotenv(vars: &[(String, String)]) -> String {
+    let mut out = String::new();
+    for (name, value) in vars {
+        let mut quoted = String::with_capacity(value.len() + 2);
+        for c in value.chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                c => quoted.push(c),
+            }
+        }
+        out.push_str(&format!("{name}=\"{quoted}\"\n"));
+    }
+    out
+}
+
+fn parse_yaml(raw: &str) -> Result<Value, serde_yaml::Error> {
+    serde_yaml::from_str(raw)
+}
+
+fn parse_toml(raw: &str) -> Result<Value, toml::de::Error> {
+    toml::from_str(raw)
+}
+
+fn detect_format(raw: &str) -> SecretFormat {
+    if matches!(serde_json::from_str(raw), Ok(Value::Object(_))) {
+        SecretFormat::Json
+    } else if parse_toml(raw).is_ok() {
+        SecretFormat::Toml
+    } else if matches!(parse_yaml(raw), Ok(Value::Object(_))) {
+        SecretFormat::Yaml
+    } else {
+        SecretFormat::Dotenv
+    }
+}
+
+fn raw_var_name(name: &str) -> Result<String> {
+    let var_name = name.rsplit('/').next().unwrap_or(name);
+    convert_env_name("", var_name)
+}
+
+/// Decodes the raw contents of a single secret that might hold binary data. Binary data can only
+/// be loaded with the `raw` format.
+#[allow(dead_code)]
+pub fn decode_env_bytes(name: &str, raw: Vec<u8>, opts: &DecodeOptions) -> Result<Secrets> {
+    match String::from_utf8(raw) {
+        Ok(s) => Ok(decode_env(name, &s, opts)?.into()),
+        Err(e) if opts.format == SecretFormat::Raw => {
+            let mut secrets = Secrets::default();
+            secrets.push_binary(raw_var_name(name)?, e.as_bytes());
+            Ok(secrets)
+        }
+        Err(_) => bail!(
+            "secret '{}' holds binary data - use the `raw` format to load it",
+            name
+        ),
+    }
+}
+
+/// Decodes the raw contents of a single secret into a list of environment variables.
+#[allow(dead_code)]
+pub fn decode_env(name: &str, raw: &str, opts: &DecodeOptions) -> Result<Vec<(String, String)>> {
+    let value = match opts.format {
+        SecretFormat::Json => serde_json::from_str(raw)
+            .with_context(|| format!("cannot decode secret '{name}' - it is not a valid JSON"))?,
+        SecretFormat::Yaml => parse_yaml(raw)
+            .with_context(|| format!("cannot decode secret '{name}' - it is not a valid YAML"))?,
+        SecretFormat::Toml => parse_toml(raw)
+            .with_context(|| format!("cannot decode secret '{name}' - it is not a valid TOML"))?,
+        SecretFormat::Dotenv => return decode_env_from_dotenv(name, raw),
+        SecretFormat::Raw => return Ok(vec![(raw_var_name(name)?, raw.to_string())]),
+        SecretFormat::Auto => {
+            let opts = DecodeOptions {
+                format: detect_format(raw),
+                ..opts.clone()
+            };
+            return decode_env(name, raw, &opts);
+        }
+    };
+    decode_env_from_json(name, value, opts)
+}
+
 #[cfg(test)]
 mod tests {
     use serde_json::json;
@@ -56,7 +298,30 @@ mod tests {
 
     macro_rules! assert_invalid_secret {
         ($a:expr) => {
-            assert!(matches!($a, Err(_)));
+            assert!($a.is_err());
+        };
+    }
+
+    // Overkill, but looks quite awesome :)
+    macro_rules! assert_decode {
+        ($a:expr, $($name:ident = $value:expr),*) => {
+            assert_decode!(DecodeOptions::default(); $a, $($name = $value),*)
+        };
+        ($opts:expr; $a:expr, $($name:ident = $value:expr),*) => {
+            #[allow(unused_variables, unused_mut)]
+            {
+                let decoded = decode_env_from_json("ignored", $a, &$opts).unwrap();
+                let len = decoded.len();
+                let mapped = decoded.into_iter().collect::<HashMap<_, _>>();
+                let mut total = 0;
+                $(
+                    total += 1;
+                    let name = stringify!($name);
+                    let value = $value.to_string();
+                    assert_eq!(Some(&value), mapped.get(&name[..]));
+                )*
+                assert_eq!(total, len);
+            }
         };
     }
 
@@ -117,26 +382,6 @@ mod tests {
 
     #[test]
     fn decode_env_from_json_correct_values() {
-        // Overkill, but looks quite awesome :)
-        macro_rules! assert_decode {
-            ($a:expr, $($name:ident = $value:expr),*) => {
-                #[allow(unused_variables, unused_mut)]
-                {
-                    let decoded = decode_env_from_json("ignored", $a).unwrap();
-                    let len = decoded.len();
-                    let mapped = decoded.into_iter().collect::<HashMap<_, _>>();
-                    let mut total = 0;
-                    $(
-                        total += 1;
-                        let name = stringify!($name);
-                        let value = $value.to_string();
-                        assert_eq!(Some(&value), mapped.get(&name[..]));
-                    )*
-                    assert_eq!(total, len);
-                }
-            };
-        }
-
         assert_decode!(json!({}),);
         assert_decode!(json!({"a": 1}), a = "1");
         assert_decode!(json!({"a": 1, "b": true}), a = "1", b = "true");
@@ -148,11 +393,65 @@ mod tests {
         );
     }
 
+    #[test]
+    fn decode_env_from_json_nested_values() {
+        let flatten = DecodeOptions {
+            nested: NestedValues::Flatten,
+            ..Default::default()
+        };
+        let stringify = DecodeOptions {
+            nested: NestedValues::Stringify,
+            ..Default::default()
+        };
+        let single = DecodeOptions {
+            nested: NestedValues::Flatten,
+            separator: "_".to_string(),
+            ..Default::default()
+        };
+
+        assert_decode!(flatten; json!({"a": {}}),);
+        assert_decode!(flatten; json!({"a": {"b": 1}}), a__b = "1");
+        assert_decode!(
+            flatten; json!({"a": {"b": {"c": true}, "d": [1, "x"]}}),
+            a__b__c = "true",
+            a__d__0 = "1",
+            a__d__1 = "x"
+        );
+        assert_decode!(single; json!({"a": {"b": 1}}), a_b = "1");
+        assert_decode!(stringify; json!({"a": {"b": 1}}), a = r#"{"b":1}"#);
+        assert_decode!(stringify; json!({"a": [1, 2], "b": 3}), a = "[1,2]", b = "3");
+
+        let invalid_separator = DecodeOptions {
+            nested: NestedValues::Flatten,
+            separator: ":".to_string(),
+            ..Default::default()
+        };
+        assert_invalid_secret!(decode_env_from_json(
+            "ignored",
+            json!({"a": {"b": 1}}),
+            &invalid_separator
+        ));
+        assert_invalid_secret!(decode_env_from_json(
+            "ignored",
+            json!({"a__b": 1, "a": {"b": 2}}),
+            &flatten
+        ));
+        assert_invalid_secret!(decode_env_from_json(
+            "ignored",
+            json!({"a": {"b_c": 1, "b": {"c": 2}}}),
+            &single
+        ));
+    }
+
     #[test]
     fn decode_env_from_json_invalid() {
         macro_rules! assert_fail {
             ($a:expr) => {
-                assert_invalid_secret!(decode_env_from_json("ignored", $a));
+                assert_invalid_secret!(decode_env_from_json(
+                    "ignored",
+                    $a,
+                    &DecodeOptions::default()
+                ));
             };
         }
 
@@ -165,6 +464,154 @@ mod tests {
         assert_fail!(json!({"a": {"b": 1}}));
     }
 
+    #[test]
+    fn decode_env_from_dotenv_correct_values() {
+        macro_rules! assert_decode_dotenv {
+            ($a:expr, $b:expr) => {
+                assert_eq!($b, decode_env_from_dotenv("ignored", $a).unwrap());
+            };
+        }
+
+        let empty: Vec<(String, String)> = vec![];
+        assert_decode_dotenv!("", empty);
+        assert_decode_dotenv!("# comment\n\n", empty);
+        assert_decode_dotenv!("A=1", vec![("A".to_string(), "1".to_string())]);
+        assert_decode_dotenv!(
+            "export A = value # comment\nB=",
+            vec![
+                ("A".to_string(), "value".to_string()),
+                ("B".to_string(), "".to_string())
+            ]
+        );
+        assert_decode_dotenv!(
+            "A=\"a \\\"b\\\" #c\\n\" # comment\nB='x \\n'",
+            vec![
+                ("A".to_string(), "a \"b\" #c\n".to_string()),
+                ("B".to_string(), "x \\n".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_env_from_dotenv_invalid() {
+        macro_rules! assert_fail {
+            ($a:expr) => {
+                assert_invalid_secret!(decode_env_from_dotenv("ignored", $a));
+            };
+        }
+
+        assert_fail!("A");
+        assert_fail!("1A=b");
+        assert_fail!("A=\"b");
+        assert_fail!("A=\"b\" c");
+        assert_fail!("A='b");
+    }
+
+    #[test]
+    fn encode_env_to_dotenv_round_trips() {
+        let vars = vec![
+            ("A".to_string(), "b c".to_string()),
+            ("B".to_string(), "\"quoted\" \\ #".to_string()),
+            ("C".to_string(), "line\nline\t".to_string()),
+            ("D".to_string(), "".to_string()),
+        ];
+        let encoded = encode_env_to_dotenv(&vars);
+        assert_eq!(
+            "A=\"b c\"\nB=\"\\\"quoted\\\" \\\\ #\"\nC=\"line\\nline\\t\"\nD=\"\"\n",
+            encoded
+        );
+        assert_eq!(vars, decode_env_from_dotenv("ignored", &encoded).unwrap());
+        let opts = DecodeOptions {
+            format: SecretFormat::Auto,
+            ..Default::default()
+        };
+        assert_eq!(vars, decode_env("ignored", &encoded, &opts).unwrap());
+    }
+
+    #[test]
+    fn decode_env_formats() {
+        macro_rules! assert_decode_format {
+            ($format:expr, $a:expr) => {
+                let opts = DecodeOptions {
+                    format: $format,
+                    ..Default::default()
+                };
+                let mut decoded = decode_env("ignored", $a, &opts).unwrap();
+                decoded.sort();
+                assert_eq!(
+                    vec![
+                        ("A".to_string(), "1".to_string()),
+                        ("B".to_string(), "b".to_string())
+                    ],
+                    decoded
+                );
+            };
+        }
+
+        assert_decode_format!(SecretFormat::Json, r#"{"A": 1, "B": "b"}"#);
+        assert_decode_format!(SecretFormat::Yaml, "A: 1\nB: b");
+        assert_decode_format!(SecretFormat::Toml, "A = 1\nB = \"b\"");
+        assert_decode_format!(SecretFormat::Dotenv, "A=1\nB=b");
+
+        assert_decode_format!(SecretFormat::Auto, r#"{"A": 1, "B": "b"}"#);
+        assert_decode_format!(SecretFormat::Auto, "A: 1\nB: b");
+        assert_decode_format!(SecretFormat::Auto, "A = 1\nB = \"b\"");
+        assert_decode_format!(SecretFormat::Auto, "A=1\nB=b");
+        assert_decode_format!(SecretFormat::Auto, "export A=1\nB=b # comment");
+    }
+
+    #[test]
+    fn decode_env_raw() {
+        let raw = DecodeOptions {
+            format: SecretFormat::Raw,
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![("DB_PASSWORD".to_string(), "{not: json}".to_string())],
+            decode_env("prod/DB-PASSWORD", "{not: json}", &raw).unwrap()
+        );
+        assert_invalid_secret!(decode_env("prod/1-PASSWORD", "value", &raw));
+    }
+
+    #[test]
+    fn decode_env_bytes_binary() {
+        let raw = DecodeOptions {
+            format: SecretFormat::Raw,
+            ..Default::default()
+        };
+        assert_eq!(
+            Secrets {
+                vars: vec![("KEY".to_string(), "/wBB".to_string())],
+                binary: vec!["KEY".to_string()],
+            },
+            decode_env_bytes("KEY", vec![0xff, 0x00, 0x41], &raw).unwrap()
+        );
+        assert_eq!(
+            Secrets::from(vec![("KEY".to_string(), "text".to_string())]),
+            decode_env_bytes("KEY", b"text".to_vec(), &raw).unwrap()
+        );
+        assert_eq!(
+            Secrets::from(vec![("A".to_string(), "1".to_string())]),
+            decode_env_bytes("KEY", br#"{"A": 1}"#.to_vec(), &Default::default()).unwrap()
+        );
+        assert_invalid_secret!(decode_env_bytes(
+            "KEY",
+            vec![0xff, 0x00, 0x41],
+            &Default::default()
+        ));
+    }
+
+    #[test]
+    fn decode_env_invalid_format() {
+        let json = DecodeOptions::default();
+        assert_invalid_secret!(decode_env("ignored", "A=1", &json));
+        let yaml = DecodeOptions {
+            format: SecretFormat::Yaml,
+            ..Default::default()
+        };
+        assert_invalid_secret!(decode_env("ignored", "- A", &yaml));
+    }
+
     #[test]
     fn convert_env_name_converts_names() {
         macro_rules! assert_convert {
@@ -1,17 +1,29 @@
 use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
-use clap::{arg, ArgGroup, Args};
+use clap::{ArgGroup, Args};
 use google_secretmanager1::{
-    hyper, hyper::client::HttpConnector, hyper_rustls, hyper_rustls::HttpsConnector, oauth2,
+    api::{AddSecretVersionRequest, Automatic, Replication, Secret, SecretPayload},
+    hyper,
+    hyper::client::HttpConnector,
+    hyper_rustls,
+    hyper_rustls::HttpsConnector,
+    oauth2,
 };
-use serde_json::Value;
 use std::path::PathBuf;
 use thiserror::Error;
+use tracing::{debug, instrument, warn};
 
-use super::{convert::decode_env_from_json, Vault, VaultConfig};
+use crate::audit::{self, AuditError};
+use crate::logging;
+use crate::retry::{self, Verdict};
+
+use super::{
+    convert::{convert_env_name, decode_env_bytes, DecodeOptions},
+    ListedSecret, Secrets, Vault, VaultConfig,
+};
 
 type SecretManager = google_secretmanager1::SecretManager<HttpsConnector<HttpConnector>>;
 
-#[derive(Args, Debug)]
+#[derive(Args, Clone, Debug)]
 #[command(group = ArgGroup::new("google_creds"))]
 pub struct GoogleConfig {
     /// Use Google Secret Manager.
@@ -56,15 +68,15 @@ pub enum GoogleError {
     #[error("Google SA configuration is invalid")]
     ConfigurationError(#[source] std::io::Error),
     #[error("secret manager operation failed")]
-    SecretManagerError(#[source] google_secretmanager1::Error),
+    SecretManagerError(#[source] Box<google_secretmanager1::Error>),
     #[error("the secret is empty")]
     EmptySecret,
     #[error("there are no secrets in the project")]
     NoSecrets,
     #[error("secret encoding is invalid")]
     WrongEncoding(#[source] anyhow::Error),
-    #[error("cannot decode secret - it is not a valid JSON")]
-    DecodeError(#[source] serde_json::Error),
+    #[error(transparent)]
+    AuditError(#[from] AuditError),
 }
 
 pub type Result<T, E = GoogleError> = std::result::Result<T, E>;
@@ -79,14 +91,36 @@ impl VaultConfig for GoogleConfig {
     fn into_vault(self) -> anyhow::Result<Self::Vault> {
         Ok(self)
     }
+
+    fn ref_vault(&self, location: Option<&str>) -> anyhow::Result<Self::Vault> {
+        let mut vault = self.clone();
+        if let Some(project) = location {
+            vault.google_project = Some(project.to_string());
+        }
+        Ok(vault)
+    }
+
+    fn credential_vars(&self) -> &'static [&'static str] {
+        &[
+            "GOOGLE_APPLICATION_CREDENTIALS",
+            "GOOGLE_APPLICATION_CREDENTIALS_JSON",
+        ]
+    }
 }
 
 impl GoogleConfig {
+    #[instrument(name = "credential", level = "debug", skip_all, fields(project = self.google_project))]
     async fn to_manager(&self) -> Result<SecretManager> {
         let auth = self
             .to_authenticator()
             .await
-            .map_err(GoogleError::ConfigurationError)?;
+            .map_err(GoogleError::ConfigurationError)
+            .inspect_err(|e| {
+                warn!(
+                    error = e as &dyn std::error::Error,
+                    "cannot set up the credentials"
+                )
+            })?;
         let manager = SecretManager::new(
             hyper::Client::builder().build(
                 hyper_rustls::HttpsConnectorBuilder::new()
@@ -105,18 +139,21 @@ impl GoogleConfig {
         &self,
     ) -> std::io::Result<oauth2::authenticator::Authenticator<HttpsConnector<HttpConnector>>> {
         if let Some(path) = &self.google_credentials_file {
+            debug!(path = %path.display(), "using the service account key file");
             let key = oauth2::read_service_account_key(path).await?;
             let auth = oauth2::ServiceAccountAuthenticator::builder(key)
                 .build()
                 .await?;
             Ok(auth)
         } else if let Some(json) = &self.google_credentials_json {
+            debug!("using the service account key from the environment");
             let key = oauth2::parse_service_account_key(json)?;
             let auth = oauth2::ServiceAccountAuthenticator::builder(key)
                 .build()
                 .await?;
             Ok(auth)
         } else {
+            debug!("using the application default credentials");
             let opts = oauth2::ApplicationDefaultCredentialsFlowOpts::default();
             let auth = match oauth2::ApplicationDefaultCredentialsAuthenticator::builder(opts).await
             {
@@ -133,44 +170,147 @@ impl GoogleConfig {
 }
 
 impl Vault for GoogleConfig {
+    #[instrument(skip(self, _opts), fields(project = self.google_project))]
     #[tokio::main]
-    async fn download_prefixed(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let mut manager = self.to_manager().await?;
-        let project = self.google_project.as_ref().unwrap();
-        let response = manager
-            .projects()
-            .secrets_list(&format!("projects/{project}"))
-            .page_size(250)
-            .doit()
-            .await
-            .map_err(GoogleError::SecretManagerError)?;
-        let secrets: Vec<_> = response
-            .1
-            .secrets
-            .ok_or(GoogleError::NoSecrets)?
-            .into_iter()
-            .filter(|f| f.name.is_some())
-            .filter(|f| self.secret_matches(prefix, f.name.as_ref().unwrap()))
-            .collect();
-        let mut from_kv = Vec::with_capacity(secrets.len());
-        for secret in secrets {
-            let value = self
-                .get_secret_full_name(&mut manager, secret.name.as_ref().unwrap())
-                .await?;
-            let name = self
-                .strip_prefix(prefix, secret.name.as_ref().unwrap())
-                .to_string();
-            from_kv.push((name, value));
-        }
-        Ok(from_kv)
+    async fn download_prefixed(
+        &self,
+        prefix: &str,
+        _opts: &DecodeOptions,
+    ) -> anyhow::Result<Secrets> {
+        retry::timeout(async {
+            let mut manager = self.to_manager().await?;
+            let secrets = self.list_names(&mut manager, prefix).await?;
+            let mut from_kv = Secrets::default();
+            for secret in secrets {
+                let value = self
+                    .get_secret_full_name(&mut manager, &secret, None)
+                    .await?;
+                let name = self.env_name(prefix, &secret)?;
+                match String::from_utf8(value) {
+                    Ok(value) => from_kv.push_text(name, value),
+                    Err(e) => from_kv.push_binary(name, e.as_bytes()),
+                }
+            }
+            Ok(from_kv)
+        })
+        .await
+    }
+
+    #[instrument(skip(self, _opts), fields(project = self.google_project))]
+    #[tokio::main]
+    async fn list_prefixed(
+        &self,
+        prefix: &str,
+        _opts: &DecodeOptions,
+    ) -> anyhow::Result<Vec<ListedSecret>> {
+        retry::timeout(async {
+            let mut manager = self.to_manager().await?;
+            let names = self.list_names(&mut manager, prefix).await?;
+            Ok(names
+                .iter()
+                .map(|name| {
+                    let var = self.env_name(prefix, name).map(|v| vec![v]);
+                    ListedSecret::new(self.strip_project(name), var)
+                })
+                .collect())
+        })
+        .await
+    }
+
+    #[instrument(skip(self, opts), fields(project = self.google_project))]
+    #[tokio::main]
+    async fn download_json(
+        &self,
+        secret_name: &str,
+        opts: &DecodeOptions,
+    ) -> anyhow::Result<Secrets> {
+        retry::timeout(async {
+            let mut manager = self.to_manager().await?;
+            let secret = self.get_secret(&mut manager, secret_name, None).await?;
+            decode_env_bytes(secret_name, secret, opts)
+        })
+        .await
+    }
+
+    #[instrument(skip(self), fields(project = self.google_project))]
+    #[tokio::main]
+    async fn download_raw(
+        &self,
+        secret_name: &str,
+        version: Option<&str>,
+    ) -> anyhow::Result<Vec<u8>> {
+        retry::timeout(async {
+            let mut manager = self.to_manager().await?;
+            Ok(self.get_secret(&mut manager, secret_name, version).await?)
+        })
+        .await
+    }
+
+    #[tokio::main]
+    async fn upload_raw(&self, secret_name: &str, value: &str) -> anyhow::Result<()> {
+        retry::timeout(async {
+            let manager = self.to_manager().await?;
+            let project = self.google_project.as_ref().unwrap();
+            let full_name = format!("projects/{project}/secrets/{secret_name}");
+            let get = || manager.projects().secrets_get(&full_name).doit();
+            match retry::retry(get, verdict).await {
+                Ok(_) => {}
+                Err(google_secretmanager1::Error::BadRequest(e)) if e["error"]["code"] == 404 => {
+                    let secret = Secret {
+                        replication: Some(Replication {
+                            automatic: Some(Automatic::default()),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    };
+                    let parent = format!("projects/{project}");
+                    let create = || {
+                        manager
+                            .projects()
+                            .secrets_create(secret.clone(), &parent)
+                            .secret_id(secret_name)
+                            .doit()
+                    };
+                    retry::retry(create, verdict)
+                        .await
+                        .map_err(|e| GoogleError::SecretManagerError(Box::new(e)))?;
+                }
+                Err(e) => return Err(GoogleError::SecretManagerError(Box::new(e)).into()),
+            }
+            let request = AddSecretVersionRequest {
+                payload: Some(SecretPayload {
+                    data: Some(base64.encode(value)),
+                    ..Default::default()
+                }),
+            };
+            // A retried request that reached the store adds another version with the same value
+            let add = || {
+                manager
+                    .projects()
+                    .secrets_add_version(request.clone(), &full_name)
+                    .doit()
+            };
+            retry::retry(add, verdict)
+                .await
+                .map_err(|e| GoogleError::SecretManagerError(Box::new(e)))?;
+            Ok(())
+        })
+        .await
     }
 
     #[tokio::main]
-    async fn download_json(&self, secret_name: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let mut manager = self.to_manager().await?;
-        let secret = self.get_secret(&mut manager, secret_name).await?;
-        let value: Value = serde_json::from_str(&secret).map_err(GoogleError::DecodeError)?;
-        decode_env_from_json(secret_name, value)
+    async fn delete_secret(&self, secret_name: &str) -> anyhow::Result<()> {
+        retry::timeout(async {
+            let manager = self.to_manager().await?;
+            let project = self.google_project.as_ref().unwrap();
+            let name = format!("projects/{project}/secrets/{secret_name}");
+            let request = || manager.projects().secrets_delete(&name).doit();
+            retry::retry(request, verdict)
+                .await
+                .map_err(|e| GoogleError::SecretManagerError(Box::new(e)))?;
+            Ok(())
+        })
+        .await
     }
 }
 
@@ -180,6 +320,42 @@ impl GoogleConfig {
         &name[(idx + 1)..]
     }
 
+    /// Full names of the secrets starting with the prefix, in alphabetical order.
+    #[instrument(level = "debug", skip(self, manager))]
+    async fn list_names(&self, manager: &mut SecretManager, prefix: &str) -> Result<Vec<String>> {
+        let project = self.google_project.as_ref().unwrap();
+        let parent = format!("projects/{project}");
+        let manager = &*manager;
+        let request = || {
+            manager
+                .projects()
+                .secrets_list(&parent)
+                .page_size(250)
+                .doit()
+        };
+        let response = retry::retry(request, verdict)
+            .await
+            .map_err(|e| GoogleError::SecretManagerError(Box::new(e)))
+            .inspect_err(|e| {
+                warn!(
+                    prefix,
+                    error = e as &dyn std::error::Error,
+                    "cannot list the secrets"
+                )
+            })?;
+        let mut names: Vec<_> = response
+            .1
+            .secrets
+            .ok_or(GoogleError::NoSecrets)?
+            .into_iter()
+            .filter_map(|f| f.name)
+            .filter(|n| self.secret_matches(prefix, n))
+            .collect();
+        names.sort();
+        debug!(count = names.len(), "listed the secrets");
+        Ok(names)
+    }
+
     fn secret_matches(&self, prefix: &str, name: &str) -> bool {
         self.strip_project(name).starts_with(prefix)
     }
@@ -188,7 +364,18 @@ impl GoogleConfig {
         &self.strip_project(name)[prefix.len()..]
     }
 
-    async fn get_secret(&self, client: &mut SecretManager, secret_name: &str) -> Result<String> {
+    /// The variable of a secret read with the prefix, with `-` replaced by `_` as for the other
+    /// secret stores.
+    fn env_name(&self, prefix: &str, name: &str) -> anyhow::Result<String> {
+        convert_env_name("", self.strip_prefix(prefix, name))
+    }
+
+    async fn get_secret(
+        &self,
+        client: &mut SecretManager,
+        secret_name: &str,
+        version: Option<&str>,
+    ) -> Result<Vec<u8>> {
         self.get_secret_full_name(
             client,
             &format!(
@@ -196,30 +383,76 @@ impl GoogleConfig {
                 self.google_project.as_ref().unwrap(),
                 secret_name
             ),
+            version,
         )
         .await
     }
 
+    #[instrument(name = "get_secret", level = "debug", skip(self, manager))]
     async fn get_secret_full_name(
         &self,
         manager: &mut SecretManager,
         name: &str,
-    ) -> Result<String> {
-        let data = manager
-            .projects()
-            .secrets_versions_access(&format!("{name}/versions/latest"))
-            .doit()
+        version: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        let version = version.unwrap_or("latest");
+        let path = format!("{name}/versions/{version}");
+        let manager = &*manager;
+        let request = || manager.projects().secrets_versions_access(&path).doit();
+        let response = retry::retry(request, verdict)
             .await
-            .map_err(GoogleError::SecretManagerError)?
-            .1
+            .map_err(|e| GoogleError::SecretManagerError(Box::new(e)))
+            .inspect_err(|e| {
+                warn!(
+                    secret = self.strip_project(name),
+                    error = e as &dyn std::error::Error,
+                    "cannot read the secret"
+                )
+            })?
+            .1;
+        // The name of the accessed version has the number instead of `latest`
+        let accessed = response.name.as_deref().unwrap_or_default();
+        let version = accessed
+            .rsplit_once("/versions/")
+            .map_or(version, |(_, v)| v);
+        debug!(version, "read the secret");
+        audit::secret_read(
+            "google",
+            self.google_project.as_deref(),
+            self.strip_project(name),
+            Some(version),
+        )?;
+        let data = response
             .payload
             .ok_or(GoogleError::EmptySecret)?
             .data
             .ok_or(GoogleError::EmptySecret)?;
-        let raw_bytes = base64
+        let value = base64
             .decode(data)
             .map_err(|e| GoogleError::WrongEncoding(anyhow::anyhow!(e)))?;
-        String::from_utf8(raw_bytes).map_err(|e| GoogleError::WrongEncoding(anyhow::anyhow!(e)))
+        logging::redact_value(&value);
+        Ok(value)
+    }
+}
+
+/// Throttled requests, server errors and failed connections are retried.
+fn verdict(error: &google_secretmanager1::Error) -> Verdict {
+    use google_secretmanager1::Error;
+    match error {
+        Error::HttpError(_) | Error::Io(_) => Verdict::Retry(None),
+        Error::BadRequest(e) => match e["error"]["code"].as_u64() {
+            Some(code) => retry::status_verdict(code as u16, None),
+            None => Verdict::Fail,
+        },
+        Error::Failure(response) => {
+            let retry_after = response
+                .headers()
+                .get(hyper::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(retry::parse_retry_after);
+            retry::status_verdict(response.status().as_u16(), retry_after)
+        }
+        _ => Verdict::Fail,
     }
 }
 
@@ -296,6 +529,26 @@ mod tests {
         assert!(!gc.secret_matches("prefix", "projects/kvenv/secrets/prefi"));
     }
 
+    #[test]
+    fn names_variables_like_other_stores() {
+        let gc = GoogleConfig {
+            enabled: true,
+            google_credentials_file: None,
+            google_credentials_json: None,
+            google_project: Some("kvenv".to_string()),
+        };
+
+        assert_eq!(
+            "db_pass",
+            gc.env_name("app-", "projects/kvenv/secrets/app-db-pass")
+                .unwrap()
+        );
+        assert!(gc
+            .env_name("app-", "projects/kvenv/secrets/app-1pass")
+            .is_err());
+        assert!(gc.env_name("app", "projects/kvenv/secrets/app-db").is_err());
+    }
+
     #[test]
     fn strips_prefix_correctly() {
         let gc = GoogleConfig {
@@ -332,8 +585,9 @@ mod tests {
         let proc_env = cfg
             .into_vault()
             .unwrap()
-            .download_json("integ-tests")
-            .unwrap();
+            .download_json("integ-tests", &DecodeOptions::default())
+            .unwrap()
+            .vars;
         assert_eq!(vec![env!("INTEGRATION_TESTS", "work")], proc_env);
     }
 
@@ -350,8 +604,9 @@ mod tests {
         let proc_env = cfg
             .into_vault()
             .unwrap()
-            .download_prefixed("prefixed-")
-            .unwrap();
+            .download_prefixed("prefixed-", &DecodeOptions::default())
+            .unwrap()
+            .vars;
         assert_eq!(
             vec![
                 env!("INTEGRATION_TESTS_A", "work1"),
@@ -1,5 +1,12 @@
-use anyhow::Result;
-use clap::{arg, ArgGroup, Args};
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
+use clap::{ArgGroup, Args, ValueEnum, ValueHint};
+use std::{
+    cell::RefCell,
+    fs,
+    path::{Path, PathBuf},
+};
+use tracing::{info, info_span};
 
 #[cfg(feature = "aws")]
 mod aws;
@@ -11,7 +18,12 @@ mod google;
 mod vault;
 
 mod convert;
+mod interpolate;
+mod pattern;
 mod process_env;
+mod schema;
+mod secret_ref;
+mod sync;
 
 #[cfg(feature = "aws")]
 use aws::AwsConfig;
@@ -22,21 +34,136 @@ use google::GoogleConfig;
 #[cfg(feature = "vault")]
 use vault::HashicorpVaultConfig;
 
-pub use process_env::ProcessEnv;
+use convert::{
+    as_valid_env_name, decode_env, decode_env_from_dotenv, DecodeOptions, NestedValues,
+    SecretFormat,
+};
+use pattern::parse_pattern;
+use process_env::ConflictPolicy;
+pub use process_env::{CacheFileError, ProcessEnv};
+use schema::Schema;
+use secret_ref::{resolve_refs, Provider};
+
+use crate::{audit, logging};
+pub use sync::{parse_location, read_location, write_location, Location};
+
+/// Variables downloaded from the secret store. If a variable is added more than once, the last
+/// value wins. The values are redacted from the log as soon as they are added.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Secrets {
+    pub vars: Vec<(String, String)>,
+    /// Names of the variables that hold base64-encoded binary data.
+    pub binary: Vec<String>,
+}
+
+#[allow(dead_code)]
+impl Secrets {
+    fn remove(&mut self, name: &str) {
+        self.vars.retain(|(k, _)| k != name);
+        self.binary.retain(|k| k != name);
+    }
+
+    pub fn push_text(&mut self, name: String, value: String) {
+        logging::redact_value(value.as_bytes());
+        self.remove(&name);
+        self.vars.push((name, value));
+    }
+
+    pub fn push_binary(&mut self, name: String, data: &[u8]) {
+        logging::redact_value(data);
+        self.remove(&name);
+        self.vars.push((name.clone(), base64.encode(data)));
+        self.binary.push(name);
+    }
+
+    pub fn extend(&mut self, other: Secrets) {
+        for (name, value) in other.vars {
+            let is_binary = other.binary.contains(&name);
+            self.remove(&name);
+            if is_binary {
+                self.binary.push(name.clone());
+            }
+            self.vars.push((name, value));
+        }
+    }
+}
+
+impl From<Vec<(String, String)>> for Secrets {
+    fn from(vars: Vec<(String, String)>) -> Self {
+        let mut secrets = Self::default();
+        for (name, value) in vars {
+            secrets.push_text(name, value);
+        }
+        secrets
+    }
+}
+
+/// A secret found in prefixed mode, without its value.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ListedSecret {
+    pub name: String,
+    /// The names of the variables the secret produces, or the reason why it cannot be used.
+    pub vars: Result<Vec<String>, String>,
+}
+
+impl ListedSecret {
+    fn new(name: impl Into<String>, vars: Result<Vec<String>>) -> Self {
+        Self {
+            name: name.into(),
+            vars: vars.map_err(|e| format!("{e:#}")),
+        }
+    }
+}
 
 pub trait Vault {
-    fn download_prefixed(&self, prefix: &str) -> Result<Vec<(String, String)>>;
-    fn download_json(&self, secret_name: &str) -> Result<Vec<(String, String)>>;
+    /// Downloads the secrets starting with the prefix. Only Hashicorp Vault stores JSON objects in
+    /// them, decoded with the `opts`.
+    fn download_prefixed(&self, prefix: &str, opts: &DecodeOptions) -> Result<Secrets>;
+    /// Lists the secrets `download_prefixed` would download, without their values.
+    fn list_prefixed(&self, prefix: &str, opts: &DecodeOptions) -> Result<Vec<ListedSecret>>;
+    fn download_json(&self, secret_name: &str, opts: &DecodeOptions) -> Result<Secrets>;
+    /// Downloads the contents of a single secret, in the latest version if not specified.
+    fn download_raw(&self, secret_name: &str, version: Option<&str>) -> Result<Vec<u8>>;
+    /// Stores the value as a new version of the secret, creating the secret if needed. Hashicorp
+    /// Vault expects a JSON object.
+    fn upload_raw(&self, secret_name: &str, value: &str) -> Result<()>;
+    /// Deletes the secret with all its versions (subject to the recovery policy of the store).
+    fn delete_secret(&self, secret_name: &str) -> Result<()>;
+    /// Checks whether the secret exists.
+    fn secret_exists(&self, secret_name: &str) -> Result<bool> {
+        let secrets = self.list_prefixed(secret_name, &DecodeOptions::default())?;
+        Ok(secrets.iter().any(|s| s.name == secret_name))
+    }
+    /// Returns the name and the value of the secret that holds the variable in prefixed mode, i.e.
+    /// the inverse of what `download_prefixed` does.
+    fn prefixed_secret(&self, prefix: &str, name: &str, value: &str) -> (String, String) {
+        (format!("{prefix}{name}"), value.to_string())
+    }
+    /// Returns the value of a secret that holds the variables in prefixed mode. Only Hashicorp
+    /// Vault stores more than one variable in a secret.
+    fn prefixed_value(&self, vars: Vec<(String, String)>) -> Result<String> {
+        let names: Vec<_> = vars.iter().map(|(k, _)| k.clone()).collect();
+        match <[_; 1]>::try_from(vars) {
+            Ok([(_, value)]) => Ok(value),
+            Err(_) => bail!("a secret cannot hold the variables {}", names.join(", ")),
+        }
+    }
 }
 
 pub trait VaultConfig {
     type Vault: Vault;
     fn is_enabled(&self) -> bool;
     fn into_vault(self) -> Result<Self::Vault>;
+    /// Creates a vault used to resolve secret references. The `location` is the part of the
+    /// reference that identifies the store (e.g. Key Vault name or Google project).
+    fn ref_vault(&self, location: Option<&str>) -> Result<Self::Vault>;
+    /// Names of the environment variables with credentials used by this backend. They are removed
+    /// from the environment of the command unless `--keep-credentials` is specified.
+    fn credential_vars(&self) -> &'static [&'static str];
 }
 
-#[derive(Args, Debug, Default)]
-#[command(group = ArgGroup::new("secret").required(true))]
+#[derive(Args, Clone, Debug, Default)]
+#[command(group = ArgGroup::new("secret").required(true).multiple(true))]
 pub struct DataConfig {
     /// The name of the secret with the environment defined. Cannot be used along `secret-prefix`.
     #[arg(
@@ -44,6 +171,8 @@ pub struct DataConfig {
         long,
         env_os = "KVENV_SECRET_NAME",
         group = "secret",
+        conflicts_with = "secret_prefix",
+        requires = "cloud",
         display_order = 1
     )]
     secret_name: Option<String>,
@@ -54,18 +183,211 @@ pub struct DataConfig {
         long,
         env = "KVENV_SECRET_PREFIX",
         group = "secret",
+        requires = "cloud",
         display_order = 2
     )]
     secret_prefix: Option<String>,
 
-    /// Environment variables that should be masked by the subsequent calls to `with`.
-    #[arg(short, long, display_order = 3)]
+    /// If set, OS environment variables with secret references as values (e.g.
+    /// `azkv://keyvault/secret` or `kvenv://aws/prod/db#password`) are replaced with the values of
+    /// referenced secrets. Can be used without `secret-name` and `secret-prefix`.
+    #[arg(long, group = "secret", display_order = 3)]
+    resolve_refs: bool,
+
+    /// Environment variables that should be masked by the subsequent calls to `with`. Supports
+    /// globs (`AWS_*`, `*_TOKEN`) and regular expressions enclosed in slashes (`/^AWS_.*$/`).
+    #[arg(short, long, value_parser = parse_pattern, display_order = 4)]
     mask: Vec<String>,
+
+    /// Only the OS environment variables matching one of the comma-separated patterns are passed
+    /// to the command, e.g. `PATH,HOME,APP_*`. Variables from the secret store are always passed.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_parser = parse_pattern,
+        conflicts_with = "inherit",
+        display_order = 5
+    )]
+    only: Vec<String>,
+
+    /// Whether the command inherits the OS environment. With `none`, the command gets only the
+    /// variables from the secret store.
+    #[arg(long, value_enum, default_value_t, display_order = 6)]
+    inherit: Inherit,
+
+    /// Pass the credentials used to access the secret store (e.g. `AZURE_CLIENT_SECRET` or
+    /// `VAULT_TOKEN`) from the OS environment to the command. They are removed by default.
+    #[arg(long, display_order = 7)]
+    keep_credentials: bool,
+
+    /// What happens when a variable from the secret store is already defined in the OS environment
+    /// with a different value.
+    #[arg(long, value_enum, default_value_t, display_order = 8)]
+    on_conflict: ConflictPolicy,
+
+    /// Environment variables that should be passed as files. The value is written to a private
+    /// temporary file and the variable is set to its path instead. The file is removed when the
+    /// command exits.
+    #[arg(long, display_order = 9)]
+    file_var: Vec<String>,
+
+    /// How binary secrets are passed to the command. `base64` stores base64-encoded data in the
+    /// variable, `file` writes the data to a temporary file (see `file-var`).
+    #[arg(long, value_enum, default_value_t, display_order = 10)]
+    binary_mode: BinaryMode,
+
+    /// Overrides `binary-mode` for a single variable, e.g. `TLS_KEY=file`.
+    #[arg(long, value_parser = parse_binary_var, display_order = 11)]
+    binary_var: Vec<(String, BinaryMode)>,
+
+    /// If set, `${VAR}` references in the downloaded values are replaced with values of other
+    /// secrets or OS environment variables. Use `$$` for a literal `$`.
+    #[arg(long, display_order = 12)]
+    interpolate: bool,
+
+    /// Path to a dotenv-like template file with additional variables, e.g.
+    /// `DATABASE_URL=postgres://${DB_USER}:${DB_PASS}@db/app`. References are resolved against
+    /// the downloaded values and the OS environment.
+    #[arg(long, value_parser, value_hint = ValueHint::FilePath, display_order = 13)]
+    template: Option<PathBuf>,
+
+    /// The format of the secret specified with `secret-name`. Ignored by Hashicorp Vault, which
+    /// stores key-value documents.
+    #[arg(long, value_enum, default_value_t, display_order = 14)]
+    secret_format: SecretFormat,
+
+    /// How nested objects and arrays in the JSON secret are handled. `flatten` creates a variable
+    /// for every nested value (e.g. `PARENT__CHILD` or `PARENT__0`), `stringify` stores them as
+    /// JSON strings.
+    #[arg(long, value_enum, default_value_t, display_order = 15)]
+    nested_values: NestedValues,
+
+    /// The separator used to join names of nested values when `nested-values` is `flatten`.
+    #[arg(long, default_value = "__", display_order = 16)]
+    flatten_separator: String,
+
+    /// Variables that have to be defined before the command is run, e.g. `DATABASE_URL,PORT`.
+    #[arg(long, value_delimiter = ',', display_order = 17)]
+    require: Vec<String>,
+
+    /// Path to a TOML file describing the variables of the command: their types (`string`, `int`,
+    /// `bool`, `url`, `enum`), allowed values and patterns. The environment is validated before
+    /// the command is run.
+    #[arg(long, value_parser, value_hint = ValueHint::FilePath, display_order = 18)]
+    schema: Option<PathBuf>,
 }
 
-#[derive(Args, Debug)]
-#[command(group = ArgGroup::new("cloud").required(true).multiple(false))]
-pub struct EnvConfig {
+/// Identifies the secrets to download, like `secret-name` and `secret-prefix`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SecretSelector {
+    Name(String),
+    Prefix(String),
+}
+
+/// Describes what is inherited from the OS environment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Inherit {
+    /// The whole OS environment is inherited (subject to `only` and `mask`).
+    #[default]
+    All,
+    /// Nothing is inherited from the OS environment.
+    None,
+}
+
+/// Describes how binary secrets are passed to the command.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BinaryMode {
+    /// The variable holds base64-encoded data.
+    #[default]
+    Base64,
+    /// The data is written to a temporary file and the variable holds its path.
+    File,
+}
+
+fn parse_binary_var(s: &str) -> Result<(String, BinaryMode)> {
+    let Some((name, mode)) = s.split_once('=') else {
+        bail!("expected `NAME=MODE`, got '{}'", s)
+    };
+    let mode = BinaryMode::from_str(mode, true).map_err(anyhow::Error::msg)?;
+    Ok((name.to_string(), mode))
+}
+
+impl DataConfig {
+    fn binary_mode(&self, name: &str) -> BinaryMode {
+        self.binary_var
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map_or(self.binary_mode, |(_, m)| *m)
+    }
+
+    fn file_vars(&self, secrets: &Secrets) -> Vec<String> {
+        let mut file_vars = self.file_var.clone();
+        for name in &secrets.binary {
+            if self.binary_mode(name) == BinaryMode::File && !file_vars.contains(name) {
+                file_vars.push(name.clone());
+            }
+        }
+        file_vars
+    }
+
+    fn only(&self) -> Option<Vec<String>> {
+        match self.inherit {
+            Inherit::None => Some(vec![]),
+            Inherit::All if !self.only.is_empty() => Some(self.only.clone()),
+            Inherit::All => None,
+        }
+    }
+
+    fn load_templates(&self) -> Result<Vec<(String, String)>> {
+        match &self.template {
+            Some(path) => {
+                let contents = fs::read_to_string(path)?;
+                decode_env_from_dotenv(&path.to_string_lossy(), &contents)
+            }
+            None => Ok(vec![]),
+        }
+    }
+
+    fn load_schema(&self) -> Result<Schema> {
+        let mut schema = match &self.schema {
+            Some(path) => Schema::from_file(path)?,
+            None => Schema::default(),
+        };
+        schema.require(&self.require);
+        Ok(schema)
+    }
+
+    fn selector(&self) -> Option<SecretSelector> {
+        if let Some(name) = &self.secret_name {
+            Some(SecretSelector::Name(name.clone()))
+        } else {
+            self.secret_prefix.clone().map(SecretSelector::Prefix)
+        }
+    }
+
+    fn download(&self, vault: &dyn Vault, selector: &SecretSelector) -> Result<Secrets> {
+        match selector {
+            SecretSelector::Name(name) => vault.download_json(name, &self.decode_options()),
+            SecretSelector::Prefix(prefix) => {
+                vault.download_prefixed(prefix, &self.decode_options())
+            }
+        }
+    }
+
+    fn decode_options(&self) -> DecodeOptions {
+        DecodeOptions {
+            format: self.secret_format,
+            nested: self.nested_values,
+            separator: self.flatten_separator.clone(),
+        }
+    }
+}
+
+/// The secret stores and the options used to access them.
+#[derive(Args, Clone, Debug)]
+#[command(group = ArgGroup::new("cloud").multiple(false))]
+pub struct StoreConfig {
     #[cfg(feature = "aws")]
     #[command(flatten)]
     aws: AwsConfig,
@@ -81,31 +403,29 @@ pub struct EnvConfig {
     #[cfg(feature = "vault")]
     #[command(flatten)]
     vault: HashicorpVaultConfig,
-
-    #[command(flatten)]
-    data: DataConfig,
 }
 
-impl EnvConfig {
-    fn into_run_config(self) -> Result<(Box<dyn Vault>, DataConfig)> {
+impl StoreConfig {
+    /// Creates the enabled secret store, if any.
+    fn into_vault(self) -> Result<Option<Box<dyn Vault>>> {
         #[cfg(feature = "aws")]
         if self.aws.is_enabled() {
-            return Ok((Box::new(self.aws.into_vault()?), self.data));
+            return Ok(Some(Box::new(self.aws.into_vault()?)));
         }
 
         #[cfg(feature = "azure")]
         if self.azure.is_enabled() {
-            return Ok((Box::new(self.azure.into_vault()?), self.data));
+            return Ok(Some(Box::new(self.azure.into_vault()?)));
         }
 
         #[cfg(feature = "google")]
         if self.google.is_enabled() {
-            return Ok((Box::new(self.google.into_vault()?), self.data));
+            return Ok(Some(Box::new(self.google.into_vault()?)));
         }
 
         #[cfg(feature = "vault")]
         if self.vault.is_enabled() {
-            return Ok((Box::new(self.vault.into_vault()?), self.data));
+            return Ok(Some(Box::new(self.vault.into_vault()?)));
         }
 
         #[cfg(not(any(
@@ -116,18 +436,450 @@ impl EnvConfig {
         )))]
         compile_error!("no cloud configured");
 
-        unreachable!()
+        Ok(None)
+    }
+
+    /// The name of the enabled backend, if any.
+    fn enabled_name(&self) -> Option<&'static str> {
+        #[cfg(feature = "aws")]
+        if self.aws.is_enabled() {
+            return Some("aws");
+        }
+        #[cfg(feature = "azure")]
+        if self.azure.is_enabled() {
+            return Some("azure");
+        }
+        #[cfg(feature = "google")]
+        if self.google.is_enabled() {
+            return Some("google");
+        }
+        #[cfg(feature = "vault")]
+        if self.vault.is_enabled() {
+            return Some("vault");
+        }
+        None
+    }
+
+    /// Names of the credential variables of the enabled backend and of the backends used to
+    /// resolve secret references.
+    fn credential_vars(&self, used: &[Provider]) -> Vec<String> {
+        let mut vars: Vec<&str> = vec![];
+        #[cfg(feature = "aws")]
+        if self.aws.is_enabled() || used.contains(&Provider::Aws) {
+            vars.extend(self.aws.credential_vars());
+        }
+        #[cfg(feature = "azure")]
+        if self.azure.is_enabled() || used.contains(&Provider::Azure) {
+            vars.extend(self.azure.credential_vars());
+        }
+        #[cfg(feature = "google")]
+        if self.google.is_enabled() || used.contains(&Provider::Google) {
+            vars.extend(self.google.credential_vars());
+        }
+        #[cfg(feature = "vault")]
+        if self.vault.is_enabled() || used.contains(&Provider::Vault) {
+            vars.extend(self.vault.credential_vars());
+        }
+        vars.into_iter().map(String::from).collect()
+    }
+
+    /// Creates the store of the provider, see `VaultConfig::ref_vault`.
+    fn ref_vault(&self, provider: Provider, location: Option<&str>) -> Result<Box<dyn Vault>> {
+        match provider {
+            #[cfg(feature = "aws")]
+            Provider::Aws => Ok(Box::new(self.aws.ref_vault(location)?)),
+            #[cfg(feature = "azure")]
+            Provider::Azure => Ok(Box::new(self.azure.ref_vault(location)?)),
+            #[cfg(feature = "google")]
+            Provider::Google => Ok(Box::new(self.google.ref_vault(location)?)),
+            #[cfg(feature = "vault")]
+            Provider::Vault => Ok(Box::new(self.vault.ref_vault(location)?)),
+            #[allow(unreachable_patterns)]
+            p => bail!("support for {:?} is not enabled in this build", p),
+        }
+    }
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct EnvConfig {
+    #[command(flatten)]
+    store: StoreConfig,
+
+    #[command(flatten)]
+    data: DataConfig,
+}
+
+impl EnvConfig {
+    /// Describes the secrets the environment is downloaded from, e.g. `aws secret 'app'`.
+    pub fn describe(&self) -> String {
+        let mut parts = vec![];
+        if let (Some(store), Some(selector)) = (self.store.enabled_name(), self.data.selector()) {
+            parts.push(match selector {
+                SecretSelector::Name(name) => format!("{store} secret '{name}'"),
+                SecretSelector::Prefix(prefix) => format!("{store} secrets with prefix '{prefix}'"),
+            });
+        }
+        if self.data.resolve_refs {
+            parts.push("secret references".to_string());
+        }
+        parts.join(", ")
+    }
+
+    fn into_run_config(self) -> Result<(Option<Box<dyn Vault>>, DataConfig)> {
+        Ok((self.store.into_vault()?, self.data))
     }
 }
 
 pub fn download_env(cfg: EnvConfig, snapshot_env: bool) -> Result<ProcessEnv> {
-    let (vault, cfg) = cfg.into_run_config()?;
-    let from_kv = if cfg.secret_name.is_some() {
-        vault.download_json(&cfg.secret_name.unwrap())?
-    } else if cfg.secret_prefix.is_some() {
-        vault.download_prefixed(&cfg.secret_prefix.unwrap())?
+    Ok(download_env_with(cfg, snapshot_env, None)?.0)
+}
+
+/// Works like `download_env`, but additionally downloads `other` from the same secret store.
+pub fn download_env_with(
+    cfg: EnvConfig,
+    snapshot_env: bool,
+    other: Option<SecretSelector>,
+) -> Result<(ProcessEnv, Option<Secrets>)> {
+    download_env_from(cfg, snapshot_env, other, StoreConfig::into_vault)
+}
+
+/// Works like `download_env_with`, with the secret store created by `open`.
+pub(crate) fn download_env_from(
+    cfg: EnvConfig,
+    snapshot_env: bool,
+    other: Option<SecretSelector>,
+    open: impl FnOnce(StoreConfig) -> Result<Option<Box<dyn Vault>>>,
+) -> Result<(ProcessEnv, Option<Secrets>)> {
+    let source = cfg.describe();
+    let _span = info_span!("download_env", source).entered();
+    let used = RefCell::new(vec![]);
+    let mut secrets = if cfg.data.resolve_refs {
+        resolve_refs(std::env::vars(), |r| {
+            used.borrow_mut().push(r.provider);
+            cfg.store.ref_vault(r.provider, r.location.as_deref())
+        })?
     } else {
-        unreachable!()
+        Secrets::default()
+    };
+    // The OS values of the resolved references are just the URIs, so they are not conflicts.
+    let mut scrubbed: Vec<_> = secrets.vars.iter().map(|(k, _)| k.clone()).collect();
+    if !cfg.data.keep_credentials {
+        scrubbed.extend(cfg.store.credential_vars(&used.borrow()));
+    }
+    let (vault, cfg) = (open(cfg.store)?, cfg.data);
+    let other = match (&vault, other) {
+        (Some(vault), Some(other)) => Some(cfg.download(vault.as_ref(), &other)?),
+        (None, Some(_)) => bail!("another secret can be downloaded only from a secret store"),
+        (_, None) => None,
+    };
+    if let (Some(vault), Some(selector)) = (&vault, cfg.selector()) {
+        secrets.extend(cfg.download(vault.as_ref(), &selector)?);
+    }
+    let file_vars = cfg.file_vars(&secrets);
+    let templates = cfg.load_templates()?;
+    let schema = cfg.load_schema()?;
+    let only = cfg.only();
+    let env = ProcessEnv::new(secrets, cfg.mask, file_vars, snapshot_env)
+        .with_interpolation(cfg.interpolate)
+        .with_templates(templates)
+        .with_only(only)
+        .with_scrubbed(scrubbed)
+        .with_conflict_policy(cfg.on_conflict)
+        .with_schema(schema);
+    logging::redact_values(env.secrets());
+    info!(
+        variables = env.secrets().len(),
+        "downloaded the environment"
+    );
+    audit::environment(&source, env.secrets())?;
+    Ok((env, other))
+}
+
+/// A secret written (or to be written in dry-run mode) by `upload_env`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Upload {
+    pub secret: String,
+    pub vars: Vec<String>,
+}
+
+/// Reads variables from a dotenv, JSON, YAML or TOML file.
+pub fn read_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let contents = fs::read_to_string(path)?;
+    let opts = DecodeOptions {
+        format: SecretFormat::Auto,
+        ..Default::default()
+    };
+    decode_env(&path.to_string_lossy(), &contents, &opts)
+}
+
+/// Prepares the secrets (name, value and the variables it holds) storing the variables.
+fn plan_upload(
+    vault: &dyn Vault,
+    selector: &SecretSelector,
+    vars: Vec<(String, String)>,
+    merge: bool,
+) -> Result<Vec<(String, String, Vec<String>)>> {
+    for (name, _) in &vars {
+        as_valid_env_name(name.clone())?;
+    }
+    match selector {
+        SecretSelector::Name(secret) => {
+            let mut doc = if merge && vault.secret_exists(secret)? {
+                match serde_json::from_slice(&vault.download_raw(secret, None)?) {
+                    Ok(serde_json::Value::Object(doc)) => doc,
+                    _ => bail!("secret '{}' is not a JSON object", secret),
+                }
+            } else {
+                serde_json::Map::new()
+            };
+            let names = vars.iter().map(|(k, _)| k.clone()).collect();
+            for (k, v) in vars {
+                doc.insert(k, serde_json::Value::String(v));
+            }
+            let value = serde_json::Value::Object(doc).to_string();
+            Ok(vec![(secret.clone(), value, names)])
+        }
+        SecretSelector::Prefix(prefix) => Ok(vars
+            .into_iter()
+            .map(|(k, v)| {
+                let (secret, value) = vault.prefixed_secret(prefix, &k, &v);
+                (secret, value, vec![k])
+            })
+            .collect()),
+    }
+}
+
+/// Stores the variables in the secret selected by `secret-name` (as a JSON object) or as separate
+/// secrets with `secret-prefix`. With `merge`, the variables are added to the current contents of
+/// the JSON secret (if it exists) instead of replacing it. Nothing is written with `dry_run`.
+pub fn upload_env(
+    cfg: EnvConfig,
+    vars: Vec<(String, String)>,
+    merge: bool,
+    dry_run: bool,
+) -> Result<Vec<Upload>> {
+    let (vault, cfg) = cfg.into_run_config()?;
+    let (Some(vault), Some(selector)) = (vault, cfg.selector()) else {
+        bail!("writing requires a secret store and either `secret-name` or `secret-prefix`");
+    };
+    let uploads = plan_upload(vault.as_ref(), &selector, vars, merge)?;
+    if !dry_run {
+        for (secret, value, _) in &uploads {
+            vault.upload_raw(secret, value)?;
+        }
+    }
+    Ok(uploads
+        .into_iter()
+        .map(|(secret, _, vars)| Upload { secret, vars })
+        .collect())
+}
+
+/// Lists the secrets selected by `secret-name` or `secret-prefix` and the variables they produce,
+/// without their values.
+pub fn list_secrets(cfg: EnvConfig) -> Result<Vec<ListedSecret>> {
+    let (vault, cfg) = cfg.into_run_config()?;
+    let (Some(vault), Some(selector)) = (vault, cfg.selector()) else {
+        bail!("listing requires a secret store and either `secret-name` or `secret-prefix`");
     };
-    Ok(ProcessEnv::new(from_kv, cfg.mask, snapshot_env))
+    match &selector {
+        SecretSelector::Prefix(prefix) => vault.list_prefixed(prefix, &cfg.decode_options()),
+        SecretSelector::Name(name) => {
+            let vars = cfg
+                .download(vault.as_ref(), &selector)
+                .map(|s| s.vars.into_iter().map(|(k, _)| k).collect());
+            Ok(vec![ListedSecret::new(name.clone(), vars)])
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use anyhow::anyhow;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn parses_binary_var() {
+        assert_eq!(
+            ("KEY".to_string(), BinaryMode::File),
+            parse_binary_var("KEY=file").unwrap()
+        );
+        assert_eq!(
+            ("KEY".to_string(), BinaryMode::Base64),
+            parse_binary_var("KEY=base64").unwrap()
+        );
+        assert!(parse_binary_var("KEY").is_err());
+        assert!(parse_binary_var("KEY=text").is_err());
+    }
+
+    #[test]
+    fn binary_secrets_become_file_vars() {
+        let secrets = Secrets {
+            vars: vec![],
+            binary: vec!["A".to_string(), "B".to_string(), "C".to_string()],
+        };
+
+        let cfg = DataConfig {
+            file_var: vec!["C".to_string(), "D".to_string()],
+            binary_var: vec![("A".to_string(), BinaryMode::File)],
+            ..Default::default()
+        };
+        assert_eq!(vec!["C", "D", "A"], cfg.file_vars(&secrets));
+
+        let cfg = DataConfig {
+            binary_mode: BinaryMode::File,
+            binary_var: vec![("A".to_string(), BinaryMode::Base64)],
+            ..Default::default()
+        };
+        assert_eq!(vec!["B", "C"], cfg.file_vars(&secrets));
+    }
+
+    #[test]
+    fn last_secret_wins() {
+        let mut secrets = Secrets::from(vec![
+            ("A".to_string(), "1".to_string()),
+            ("B".to_string(), "1".to_string()),
+            ("A".to_string(), "2".to_string()),
+        ]);
+        secrets.push_binary("B".to_string(), b"2");
+
+        let mut other = Secrets::default();
+        other.push_binary("A".to_string(), b"3");
+        other.push_text("B".to_string(), "3".to_string());
+        other.push_text("C".to_string(), "3".to_string());
+        secrets.extend(other);
+
+        assert_eq!(
+            Secrets {
+                vars: vec![
+                    ("A".to_string(), "Mw==".to_string()),
+                    ("B".to_string(), "3".to_string()),
+                    ("C".to_string(), "3".to_string()),
+                ],
+                binary: vec!["A".to_string()],
+            },
+            secrets
+        );
+    }
+
+    /// An in-memory secret store, which keeps a variable per secret in prefixed mode like AWS.
+    pub(crate) struct MemoryVault(RefCell<BTreeMap<String, Vec<u8>>>);
+
+    impl MemoryVault {
+        pub(crate) fn new<K: Into<String>, V: Into<Vec<u8>>>(
+            secrets: impl IntoIterator<Item = (K, V)>,
+        ) -> Self {
+            let secrets = secrets.into_iter().map(|(k, v)| (k.into(), v.into()));
+            Self(RefCell::new(secrets.collect()))
+        }
+    }
+
+    impl Vault for MemoryVault {
+        fn download_prefixed(&self, prefix: &str, _: &DecodeOptions) -> Result<Secrets> {
+            let mut secrets = Secrets::default();
+            for (name, value) in self.0.borrow().iter() {
+                if !name.starts_with(prefix) {
+                    continue;
+                }
+                let var = convert::convert_env_name(prefix, name)?;
+                match std::str::from_utf8(value) {
+                    Ok(text) => secrets.push_text(var, text.to_string()),
+                    Err(_) => secrets.push_binary(var, value),
+                }
+            }
+            Ok(secrets)
+        }
+
+        fn list_prefixed(&self, prefix: &str, _: &DecodeOptions) -> Result<Vec<ListedSecret>> {
+            Ok(self
+                .0
+                .borrow()
+                .keys()
+                .filter(|name| name.starts_with(prefix))
+                .map(|name| {
+                    let var = convert::convert_env_name(prefix, name);
+                    ListedSecret::new(name.clone(), var.map(|v| vec![v]))
+                })
+                .collect())
+        }
+
+        fn download_json(&self, secret_name: &str, opts: &DecodeOptions) -> Result<Secrets> {
+            let raw = String::from_utf8(self.download_raw(secret_name, None)?)?;
+            Ok(decode_env(secret_name, &raw, opts)?.into())
+        }
+
+        fn download_raw(&self, secret_name: &str, _: Option<&str>) -> Result<Vec<u8>> {
+            let secrets = self.0.borrow();
+            let value = secrets.get(secret_name);
+            value
+                .cloned()
+                .ok_or_else(|| anyhow!("secret '{secret_name}' not found"))
+        }
+
+        fn upload_raw(&self, secret_name: &str, value: &str) -> Result<()> {
+            self.0
+                .borrow_mut()
+                .insert(secret_name.to_string(), value.as_bytes().to_vec());
+            Ok(())
+        }
+
+        fn delete_secret(&self, secret_name: &str) -> Result<()> {
+            self.0.borrow_mut().remove(secret_name);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn plans_uploads() {
+        let vault = MemoryVault::new([("app", r#"{"A":"1","B":"1"}"#)]);
+        let vars = || {
+            vec![
+                ("B".to_string(), "2".to_string()),
+                ("C".to_string(), "2".to_string()),
+            ]
+        };
+        let name = SecretSelector::Name("app".to_string());
+
+        assert_eq!(
+            vec![(
+                "app".to_string(),
+                r#"{"A":"1","B":"2","C":"2"}"#.to_string(),
+                vec!["B".to_string(), "C".to_string()]
+            )],
+            plan_upload(&vault, &name, vars(), true).unwrap()
+        );
+        assert_eq!(
+            r#"{"B":"2","C":"2"}"#,
+            plan_upload(&vault, &name, vars(), false).unwrap()[0].1
+        );
+        assert_eq!(
+            vec![
+                ("app-B".to_string(), "2".to_string(), vec!["B".to_string()]),
+                ("app-C".to_string(), "2".to_string(), vec!["C".to_string()]),
+            ],
+            plan_upload(
+                &vault,
+                &SecretSelector::Prefix("app-".to_string()),
+                vars(),
+                false
+            )
+            .unwrap()
+        );
+
+        assert_eq!(
+            r#"{"B":"2","C":"2"}"#,
+            plan_upload(
+                &vault,
+                &SecretSelector::Name("new".to_string()),
+                vars(),
+                true
+            )
+            .unwrap()[0]
+                .1
+        );
+
+        let invalid = vec![("1A".to_string(), "x".to_string())];
+        assert!(plan_upload(&vault, &name, invalid, false).is_err());
+    }
 }
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use regex::Regex;
+
+/// A pattern matching environment variable names. Regular expressions are enclosed in slashes
+/// (`/^AWS_.*$/`), everything else is a glob where `*` matches any number of characters and `?`
+/// matches a single character.
+pub enum Pattern {
+    Glob(Vec<char>),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self> {
+        match pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+            Some(re) => Ok(Self::Regex(Regex::new(re)?)),
+            None => Ok(Self::Glob(pattern.chars().collect())),
+        }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Glob(p) => glob_matches(p, &name.chars().collect::<Vec<_>>()),
+            Self::Regex(re) => re.is_match(name),
+        }
+    }
+}
+
+fn glob_matches(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            }
+            Some('?') => {
+                p += 1;
+                n += 1;
+            }
+            Some(c) if *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    p = bp + 1;
+                    n = bn + 1;
+                    backtrack = Some((bp, bn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Validates the pattern passed on the command line.
+pub fn parse_pattern(s: &str) -> Result<String> {
+    Pattern::new(s)?;
+    Ok(s.to_string())
+}
+
+pub struct Patterns(Vec<Pattern>);
+
+impl Patterns {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self> {
+        patterns
+            .iter()
+            .map(|p| Pattern::new(p.as_ref()))
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.0.iter().any(|p| p.matches(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_patterns() {
+        macro_rules! assert_match {
+            ($p:expr, $n:expr, $r:expr) => {
+                assert_eq!($r, Pattern::new($p).unwrap().matches($n), "{} ~ {}", $p, $n);
+            };
+        }
+
+        assert_match!("PATH", "PATH", true);
+        assert_match!("PATH", "PATHS", false);
+        assert_match!("PATH", "XPATH", false);
+        assert_match!("AWS_*", "AWS_SECRET_ACCESS_KEY", true);
+        assert_match!("AWS_*", "AWS_", true);
+        assert_match!("AWS_*", "MY_AWS_KEY", false);
+        assert_match!("*_TOKEN", "VAULT_TOKEN", true);
+        assert_match!("*_TOKEN", "VAULT_TOKEN_FILE", false);
+        assert_match!("*TOKEN*", "A_TOKEN_B", true);
+        assert_match!("A*B*C", "AxxBxxBxxC", true);
+        assert_match!("A*B*C", "AxxBxxCxxD", false);
+        assert_match!("A?C", "ABC", true);
+        assert_match!("A?C", "AC", false);
+        assert_match!("*", "", true);
+    }
+
+    #[test]
+    fn regex_patterns() {
+        let p = Pattern::new("/^(AWS|AZURE)_.*$/").unwrap();
+        assert!(p.matches("AWS_REGION"));
+        assert!(p.matches("AZURE_CLIENT_SECRET"));
+        assert!(!p.matches("GOOGLE_PROJECT"));
+
+        assert!(Pattern::new("/(/").is_err());
+        assert!(parse_pattern("/[/").is_err());
+        assert!(Pattern::new("/").unwrap().matches("/"));
+    }
+
+    #[test]
+    fn multiple_patterns() {
+        let p = Patterns::new(&["PATH", "APP_*"]).unwrap();
+        assert!(p.matches("PATH"));
+        assert!(p.matches("APP_NAME"));
+        assert!(!p.matches("HOME"));
+        assert!(!Patterns::new::<&str>(&[]).unwrap().matches("HOME"));
+    }
+}
@@ -1,14 +1,122 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use thiserror::Error;
+use tracing::warn;
 
-#[derive(Debug, Serialize, Deserialize)]
+use super::{interpolate::interpolate, pattern::Patterns, schema::Schema, Secrets};
+use crate::integrity::KEY_VARS;
+
+/// Describes what happens when a variable from the secret store is already defined in the OS
+/// environment with a different value.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    /// The value from the secret store is used.
+    #[default]
+    Override,
+    /// The value from the OS environment is used.
+    KeepOs,
+    /// The command is not run.
+    Error,
+    /// The value from the secret store is used and a warning is printed.
+    Warn,
+}
+
+impl ConflictPolicy {
+    fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("variables from the secret store conflict with the OS environment: {}", .0.join(", "))]
+pub struct ConflictError(Vec<String>);
+
+/// The version of the cache file format written by this `kvenv`. Files without a version (written
+/// before the format was versioned) are version 0.
+pub const FORMAT_VERSION: u64 = 1;
+
+#[derive(Error, Debug)]
+pub enum CacheFileError {
+    #[error("the file was written by kvenv {kvenv} in format version {format}, but this kvenv supports versions up to {FORMAT_VERSION} - upgrade kvenv")]
+    Newer { format: u64, kvenv: String },
+    #[error("the format version of the file is invalid")]
+    InvalidVersion,
+    #[error("the file has been modified since it was cached (the integrity check failed)")]
+    Tampered,
+    #[error("the file is not authenticated - it was cached without the integrity key")]
+    Unauthenticated,
+    #[error("the file is authenticated, but the integrity key was not given")]
+    MissingKey,
+    #[error("the file is not a valid cached environment")]
+    Json(#[from] serde_json::Error),
+}
+
+/// The contents of a cache file: the environment and the information about where it comes from.
+#[derive(Serialize, Deserialize)]
+struct Envelope<E> {
+    format: u64,
+    /// The version of `kvenv` that wrote the file.
+    #[serde(default)]
+    kvenv: String,
+    /// Seconds since the Unix epoch.
+    #[serde(default)]
+    created_at: u64,
+    /// The secrets the environment was downloaded from.
+    #[serde(default)]
+    source: String,
+    env: E,
+}
+
+/// Computes the HMAC of the contents of a cache file (without the `mac` field). The contents are
+/// serialized from `Value`, so the result does not depend on the formatting of the file.
+fn mac(key: &[u8], doc: &Value) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&serde_json::to_vec(doc).expect("values are serializable"));
+    mac
+}
+
+/// Checks the `mac` field of the contents of a cache file and removes it.
+fn verify(doc: &mut Value, key: Option<&[u8]>) -> Result<(), CacheFileError> {
+    let tag = doc.as_object_mut().and_then(|d| d.remove("mac"));
+    match (key, tag) {
+        (Some(key), Some(Value::String(tag))) => {
+            let tag = hex::decode(tag).map_err(|_| CacheFileError::Tampered)?;
+            mac(key, doc)
+                .verify_slice(&tag)
+                .map_err(|_| CacheFileError::Tampered)
+        }
+        (Some(_), Some(_)) => Err(CacheFileError::Tampered),
+        (Some(_), None) => Err(CacheFileError::Unauthenticated),
+        (None, Some(_)) => Err(CacheFileError::MissingKey),
+        (None, None) => Ok(()),
+    }
+}
+
+/// Upgrades the contents of a cache file written in an older format to the current one.
+fn migrate(mut doc: Value, format: u64) -> Value {
+    if format < 1 {
+        // The environment itself, without the envelope
+        doc = json!({ "format": 1, "env": doc });
+    }
+    doc
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 enum OsEnv {
     Persisted(Vec<(String, String)>),
     Fresh(Vec<(String, String)>),
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ProcessEnv {
     #[serde(
         skip_serializing_if = "OsEnv::should_not_persist",
@@ -17,11 +125,29 @@ pub struct ProcessEnv {
     from_env: OsEnv,
     from_kv: Vec<(String, String)>,
     masked: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    file_vars: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    binary: Vec<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    interpolate: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    templates: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    only: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    scrubbed: Vec<String>,
+    #[serde(default, skip_serializing_if = "ConflictPolicy::is_default")]
+    on_conflict: ConflictPolicy,
+    #[serde(default, skip_serializing_if = "Schema::is_empty")]
+    schema: Schema,
 }
 
 impl OsEnv {
     fn new(persisted: bool) -> Self {
-        let env = std::env::vars().collect();
+        let env = std::env::vars()
+            .filter(|(k, _)| !KEY_VARS.contains(&k.as_str()))
+            .collect();
         if persisted {
             Self::Persisted(env)
         } else {
@@ -51,29 +177,186 @@ impl IntoIterator for OsEnv {
 }
 
 impl ProcessEnv {
-    pub fn new(from_kv: Vec<(String, String)>, masked: Vec<String>, snapshot_env: bool) -> Self {
+    pub fn new(
+        secrets: Secrets,
+        masked: Vec<String>,
+        file_vars: Vec<String>,
+        snapshot_env: bool,
+    ) -> Self {
         Self {
             from_env: OsEnv::new(snapshot_env),
-            from_kv,
+            from_kv: secrets.vars,
             masked,
+            file_vars,
+            binary: secrets.binary,
+            interpolate: false,
+            templates: vec![],
+            only: None,
+            scrubbed: vec![],
+            on_conflict: ConflictPolicy::default(),
+            schema: Schema::default(),
+        }
+    }
+
+    /// Restricts the variables inherited from the OS environment to the ones matching the
+    /// patterns. `Some(vec![])` means that nothing is inherited.
+    pub fn with_only(self, only: Option<Vec<String>>) -> Self {
+        Self { only, ..self }
+    }
+
+    /// Drops the variables from the OS environment before anything else happens, so they are
+    /// neither passed to the command nor available for interpolation. Unlike masks, this does not
+    /// affect variables coming from the secret store.
+    pub fn with_scrubbed(self, scrubbed: Vec<String>) -> Self {
+        Self { scrubbed, ..self }
+    }
+
+    /// Sets what happens when a variable from the secret store overrides the OS environment.
+    pub fn with_conflict_policy(self, on_conflict: ConflictPolicy) -> Self {
+        Self {
+            on_conflict,
+            ..self
+        }
+    }
+
+    /// Sets the schema the final environment is validated against.
+    pub fn with_schema(self, schema: Schema) -> Self {
+        Self { schema, ..self }
+    }
+
+    /// Enables `${VAR}` interpolation in the values downloaded from the secret store.
+    pub fn with_interpolation(self, interpolate: bool) -> Self {
+        Self {
+            interpolate,
+            ..self
         }
     }
 
-    pub fn from_reader<R: std::io::Read>(rdr: R) -> serde_json::Result<Self> {
-        serde_json::from_reader(rdr)
+    /// Adds variables that are rendered against the final environment (after interpolation, but
+    /// before masking).
+    pub fn with_templates(self, templates: Vec<(String, String)>) -> Self {
+        Self { templates, ..self }
+    }
+
+    /// Variables downloaded from the secret store (including the resolved references).
+    pub fn secrets(&self) -> &[(String, String)] {
+        &self.from_kv
+    }
+
+    pub fn file_vars(&self) -> &[String] {
+        &self.file_vars
+    }
+
+    /// Names of the variables that hold base64-encoded binary data.
+    pub fn binary_vars(&self) -> &[String] {
+        &self.binary
+    }
+
+    /// Reads a cache file written by `to_writer` of this or an older `kvenv`. With `key`, the file
+    /// must have been written with the same key and not modified since.
+    pub fn from_reader<R: std::io::Read>(
+        rdr: R,
+        key: Option<&[u8]>,
+    ) -> Result<Self, CacheFileError> {
+        let doc: Value = serde_json::from_reader(rdr)?;
+        let format = match doc.get("format") {
+            None => 0,
+            Some(format) => format.as_u64().ok_or(CacheFileError::InvalidVersion)?,
+        };
+        if format > FORMAT_VERSION {
+            let kvenv = doc.get("kvenv").and_then(Value::as_str).unwrap_or("?");
+            return Err(CacheFileError::Newer {
+                format,
+                kvenv: kvenv.to_string(),
+            });
+        }
+        let mut doc = migrate(doc, format);
+        verify(&mut doc, key)?;
+        let envelope: Envelope<Self> = serde_json::from_value(doc)?;
+        Ok(envelope.env)
     }
 
-    pub fn to_writer<W: std::io::Write>(&self, w: W) -> serde_json::Result<()> {
-        serde_json::to_writer(w, self)
+    /// Writes the environment as a cache file. `source` describes where the secrets come from, with
+    /// `key` the file is authenticated with an HMAC.
+    pub fn to_writer<W: std::io::Write>(
+        &self,
+        w: W,
+        source: &str,
+        key: Option<&[u8]>,
+    ) -> serde_json::Result<()> {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let envelope = Envelope {
+            format: FORMAT_VERSION,
+            kvenv: env!("CARGO_PKG_VERSION").to_string(),
+            created_at,
+            source: source.to_string(),
+            env: self,
+        };
+        let mut doc = serde_json::to_value(envelope)?;
+        if let Some(key) = key {
+            doc["mac"] = Value::String(hex::encode(mac(key, &doc).finalize().into_bytes()));
+        }
+        serde_json::to_writer(w, &doc)
     }
 
-    pub fn into_env(self) -> HashMap<String, String> {
-        let mut map: HashMap<_, _> = self.from_env.into_iter().collect();
-        map.extend(self.from_kv);
-        for m in self.masked {
-            map.remove(&m);
+    pub fn into_env(self) -> anyhow::Result<HashMap<String, String>> {
+        let masked = Patterns::new(&self.masked)?;
+        let only = self.only.as_deref().map(Patterns::new).transpose()?;
+
+        let mut map: HashMap<_, _> = self
+            .from_env
+            .into_iter()
+            .filter(|(k, _)| !self.scrubbed.contains(k) && !KEY_VARS.contains(&k.as_str()))
+            .collect();
+        let mut inherited: HashSet<_> = map.keys().cloned().collect();
+        let from_kv = if self.interpolate {
+            interpolate(self.from_kv, &map)?
+        } else {
+            self.from_kv
+        };
+        let mut conflicts = vec![];
+        for (k, v) in from_kv {
+            if inherited.contains(&k) && map[&k] != v && !masked.matches(&k) {
+                conflicts.push(k.clone());
+                if self.on_conflict == ConflictPolicy::KeepOs {
+                    // The OS value stands in for the secret, so `--only` must not drop it
+                    inherited.remove(&k);
+                    continue;
+                }
+            }
+            inherited.remove(&k);
+            map.insert(k, v);
+        }
+        conflicts.sort();
+        match self.on_conflict {
+            ConflictPolicy::Error if !conflicts.is_empty() => {
+                return Err(ConflictError(conflicts).into())
+            }
+            ConflictPolicy::Warn => {
+                for k in conflicts {
+                    warn!(
+                        variable = %k,
+                        "variable from the secret store overrides the OS environment"
+                    );
+                }
+            }
+            _ => {}
+        }
+        if !self.templates.is_empty() {
+            for (k, v) in interpolate(self.templates, &map)? {
+                inherited.remove(&k);
+                map.insert(k, v);
+            }
         }
-        map
+
+        map.retain(|k, _| {
+            let allowed = !inherited.contains(k) || only.as_ref().is_none_or(|o| o.matches(k));
+            allowed && !masked.matches(k) && !KEY_VARS.contains(&k.as_str())
+        });
+        self.schema.validate(&map)?;
+        Ok(map)
     }
 }
 
@@ -93,9 +376,25 @@ mod tests {
                 from_env: OsEnv::Fresh(from_env),
                 from_kv,
                 masked,
+                file_vars: vec![],
+                binary: vec![],
+                interpolate: false,
+                templates: vec![],
+                only: None,
+                scrubbed: vec![],
+                on_conflict: ConflictPolicy::default(),
+                schema: Schema::default(),
             }
         }
 
+        pub fn with_file_vars(self, file_vars: Vec<String>) -> Self {
+            Self { file_vars, ..self }
+        }
+
+        pub fn with_binary_vars(self, binary: Vec<String>) -> Self {
+            Self { binary, ..self }
+        }
+
         pub fn from_str(s: &str) -> Self {
             serde_json::from_str(s).unwrap()
         }
@@ -127,9 +426,17 @@ mod tests {
                 env!("E", "KV"),
             ],
             masked: vec![env!("B"), env!("E")],
+            file_vars: vec![],
+            binary: vec![],
+            interpolate: false,
+            templates: vec![],
+            only: None,
+            scrubbed: vec![],
+            on_conflict: ConflictPolicy::default(),
+            schema: Schema::default(),
         };
 
-        let env = env.into_env();
+        let env = env.into_env().unwrap();
 
         assert_eq!(Some(&env!("KV")), env.get("A"));
         assert_eq!(None, env.get("B"));
@@ -138,12 +445,183 @@ mod tests {
         assert_eq!(None, env.get("E"));
     }
 
+    #[test]
+    fn into_env_patterns() {
+        let env = ProcessEnv::fresh(
+            vec![
+                env!("PATH", "/bin"),
+                env!("HOME", "/home"),
+                env!("APP_NAME", "app"),
+                env!("AWS_SECRET_ACCESS_KEY", "secret"),
+                env!("OVERRIDDEN", "env"),
+            ],
+            vec![
+                env!("FROM_KV", "kv"),
+                env!("OVERRIDDEN", "kv"),
+                env!("APP_TOKEN", "kv"),
+            ],
+            vec![env!("*_TOKEN"), env!("/^HOME$/")],
+        );
+
+        let only = |only| {
+            let mut keys: Vec<_> = env
+                .clone()
+                .with_only(only)
+                .into_env()
+                .unwrap()
+                .into_keys()
+                .collect();
+            keys.sort();
+            keys
+        };
+
+        assert_eq!(
+            vec![
+                "APP_NAME",
+                "AWS_SECRET_ACCESS_KEY",
+                "FROM_KV",
+                "OVERRIDDEN",
+                "PATH"
+            ],
+            only(None)
+        );
+        assert_eq!(
+            vec!["APP_NAME", "FROM_KV", "OVERRIDDEN", "PATH"],
+            only(Some(vec![env!("PATH"), env!("HOME"), env!("APP_*")]))
+        );
+        assert_eq!(vec!["FROM_KV", "OVERRIDDEN"], only(Some(vec![])));
+    }
+
+    #[test]
+    fn into_env_scrubbed() {
+        let env = ProcessEnv::fresh(
+            vec![
+                env!("VAULT_TOKEN", "os"),
+                env!("AWS_SECRET_ACCESS_KEY", "os"),
+            ],
+            vec![
+                env!("AWS_SECRET_ACCESS_KEY", "kv"),
+                env!("A", "${VAULT_TOKEN}"),
+            ],
+            vec![],
+        )
+        .with_scrubbed(vec![env!("VAULT_TOKEN"), env!("AWS_SECRET_ACCESS_KEY")]);
+
+        assert_eq!(
+            HashMap::from([
+                env!("AWS_SECRET_ACCESS_KEY", "kv"),
+                env!("A", "${VAULT_TOKEN}")
+            ]),
+            env.clone().into_env().unwrap()
+        );
+        assert!(env.with_interpolation(true).into_env().is_err());
+    }
+
+    #[test]
+    fn into_env_conflicts() {
+        let env = ProcessEnv::fresh(
+            vec![env!("A", "os"), env!("B", "same"), env!("C", "os")],
+            vec![env!("A", "kv"), env!("B", "same"), env!("C", "kv")],
+            vec![env!("C")],
+        );
+        let with_policy = |policy| env.clone().with_conflict_policy(policy).into_env();
+
+        let expected = HashMap::from([env!("A", "kv"), env!("B", "same")]);
+        assert_eq!(expected, with_policy(ConflictPolicy::Override).unwrap());
+        assert_eq!(expected, with_policy(ConflictPolicy::Warn).unwrap());
+        assert_eq!(
+            HashMap::from([env!("A", "os"), env!("B", "same")]),
+            with_policy(ConflictPolicy::KeepOs).unwrap()
+        );
+        assert_eq!(
+            HashMap::from([env!("A", "os"), env!("B", "same")]),
+            env.clone()
+                .with_conflict_policy(ConflictPolicy::KeepOs)
+                .with_only(Some(vec![]))
+                .into_env()
+                .unwrap()
+        );
+        assert_eq!(
+            "variables from the secret store conflict with the OS environment: A",
+            with_policy(ConflictPolicy::Error).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn into_env_interpolated() {
+        let env = ProcessEnv::fresh(
+            vec![env!("HOST", "localhost"), env!("HOME", "/home")],
+            vec![
+                env!("USER", "user"),
+                env!("PASS", "${HOME}/pass"),
+                env!("RAW", "${USER}"),
+            ],
+            vec![env!("PASS")],
+        )
+        .with_interpolation(true)
+        .with_templates(vec![env!("DSN", "pg://${USER}:${PASS}@${HOST}")]);
+
+        let env = env.into_env().unwrap();
+
+        assert_eq!(Some(&env!("user")), env.get("RAW"));
+        assert_eq!(
+            Some(&env!("pg://user:/home/pass@localhost")),
+            env.get("DSN")
+        );
+        assert_eq!(None, env.get("PASS"));
+    }
+
+    #[test]
+    fn into_env_not_interpolated() {
+        let env = ProcessEnv::fresh(vec![], vec![env!("A", "${B}")], vec![])
+            .with_templates(vec![env!("C", "${A}")]);
+
+        let env = env.into_env().unwrap();
+
+        assert_eq!(Some(&env!("${B}")), env.get("A"));
+        assert_eq!(Some(&env!("${B}")), env.get("C"));
+    }
+
+    #[test]
+    fn into_env_without_integrity_key() {
+        let env = ProcessEnv::fresh(
+            vec![
+                env!("KVENV_INTEGRITY_KEY", "os"),
+                env!("KVENV_INTEGRITY_KEY_FILE", "/key"),
+            ],
+            vec![env!("A", "${KVENV_INTEGRITY_KEY}")],
+            vec![],
+        );
+        assert_eq!(
+            HashMap::from([env!("A", "${KVENV_INTEGRITY_KEY}")]),
+            env.clone().into_env().unwrap()
+        );
+        assert!(env.with_interpolation(true).into_env().is_err());
+        let from_kv = ProcessEnv::fresh(vec![], vec![env!("KVENV_INTEGRITY_KEY", "kv")], vec![]);
+        assert!(from_kv.into_env().unwrap().is_empty());
+
+        std::env::set_var("KVENV_INTEGRITY_KEY", "snapshot");
+        let snapshot = OsEnv::new(true);
+        std::env::remove_var("KVENV_INTEGRITY_KEY");
+        assert!(!snapshot
+            .into_iter()
+            .any(|(k, _)| k == "KVENV_INTEGRITY_KEY"));
+    }
+
     #[test]
     fn serialization_persisted() {
         let persisted = |env, kv, masked| ProcessEnv {
             from_env: OsEnv::Persisted(env),
             from_kv: kv,
             masked,
+            file_vars: vec![],
+            binary: vec![],
+            interpolate: false,
+            templates: vec![],
+            only: None,
+            scrubbed: vec![],
+            on_conflict: ConflictPolicy::default(),
+            schema: Schema::default(),
         };
 
         let test = |env: &ProcessEnv| {
@@ -170,6 +648,14 @@ mod tests {
             from_env: OsEnv::Fresh(vec![env!("Ignore", "me")]),
             from_kv: kv,
             masked,
+            file_vars: vec![],
+            binary: vec![],
+            interpolate: false,
+            templates: vec![],
+            only: None,
+            scrubbed: vec![],
+            on_conflict: ConflictPolicy::default(),
+            schema: Schema::default(),
         };
 
         let test = |env: &ProcessEnv| {
@@ -191,4 +677,69 @@ mod tests {
             serialized.from_env.into_iter().collect::<Vec<_>>()
         );
     }
+
+    #[test]
+    fn versioned_cache_files() {
+        let env = ProcessEnv::fresh(vec![], vec![env!("A", "KV")], vec![env!("M")]);
+        let mut data = vec![];
+        env.to_writer(&mut data, "aws secret 'app'", None).unwrap();
+
+        let doc: Value = serde_json::from_slice(&data).unwrap();
+        assert_eq!(json!(FORMAT_VERSION), doc["format"]);
+        assert_eq!(json!(std::env!("CARGO_PKG_VERSION")), doc["kvenv"]);
+        assert_eq!(json!("aws secret 'app'"), doc["source"]);
+        assert!(doc["created_at"].as_u64().unwrap() > 0);
+        let read = ProcessEnv::from_reader(data.as_slice(), None).unwrap();
+        assert_eq!(env.from_kv, read.from_kv);
+        assert_eq!(env.masked, read.masked);
+
+        let legacy =
+            r#"{"from_env":{"Persisted":[["A","ENV"]]},"from_kv":[["A","KV"]],"masked":["M"]}"#;
+        let read = ProcessEnv::from_reader(legacy.as_bytes(), None).unwrap();
+        assert_eq!(env.from_kv, read.from_kv);
+        assert!(matches!(read.from_env, OsEnv::Persisted(_)));
+
+        let newer = r#"{"format":99,"kvenv":"9.0.0","env":{"something":"else"}}"#;
+        let err = ProcessEnv::from_reader(newer.as_bytes(), None).unwrap_err();
+        assert!(matches!(err, CacheFileError::Newer { format: 99, .. }));
+        assert!(err.to_string().contains("kvenv 9.0.0"));
+
+        let invalid = r#"{"format":"1","env":{}}"#;
+        assert!(matches!(
+            ProcessEnv::from_reader(invalid.as_bytes(), None),
+            Err(CacheFileError::InvalidVersion)
+        ));
+    }
+
+    #[test]
+    fn authenticated_cache_files() {
+        let env = ProcessEnv::fresh(vec![], vec![env!("A", "KV")], vec![]);
+        let read = |data: &[u8], key: Option<&[u8]>| ProcessEnv::from_reader(data, key);
+        let mut data = vec![];
+        env.to_writer(&mut data, "", Some(b"key")).unwrap();
+
+        assert_eq!(env.from_kv, read(&data, Some(b"key")).unwrap().from_kv);
+        assert!(matches!(
+            read(&data, Some(b"other")),
+            Err(CacheFileError::Tampered)
+        ));
+        assert!(matches!(read(&data, None), Err(CacheFileError::MissingKey)));
+
+        // Reformatting does not matter, changing the contents does
+        let mut doc: Value = serde_json::from_slice(&data).unwrap();
+        let pretty = serde_json::to_vec_pretty(&doc).unwrap();
+        assert!(read(&pretty, Some(b"key")).is_ok());
+        doc["env"]["from_kv"] = json!([["LD_PRELOAD", "/tmp/evil.so"]]);
+        let tampered = serde_json::to_vec(&doc).unwrap();
+        assert!(matches!(
+            read(&tampered, Some(b"key")),
+            Err(CacheFileError::Tampered)
+        ));
+        doc.as_object_mut().unwrap().remove("mac");
+        let stripped = serde_json::to_vec(&doc).unwrap();
+        assert!(matches!(
+            read(&stripped, Some(b"key")),
+            Err(CacheFileError::Unauthenticated)
+        ));
+    }
 }
@@ -0,0 +1,293 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    path::Path,
+};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The type of the value of a variable.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VarType {
+    #[default]
+    String,
+    Int,
+    Bool,
+    Url,
+    Enum,
+}
+
+/// The specification of a single variable.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VarSpec {
+    #[serde(default, rename = "type")]
+    kind: VarType,
+    /// The allowed values of an `enum` variable.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    values: Vec<String>,
+    /// The regular expression the value has to match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pattern: Option<String>,
+    /// If set, the variable does not have to be defined, but is validated when it is.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    optional: bool,
+}
+
+/// Variables that need to be present in the environment of the command, e.g.
+///
+/// ```toml
+/// [PORT]
+/// type = "int"
+///
+/// [LOG_LEVEL]
+/// type = "enum"
+/// values = ["debug", "info", "error"]
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Schema(BTreeMap<String, VarSpec>);
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Violation {
+    Missing(String),
+    WrongType(String, VarType),
+    NotInEnum(String, Vec<String>),
+    NoMatch(String, String),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Missing(name) => write!(f, "{name}: is not defined"),
+            Self::WrongType(name, VarType::Int) => write!(f, "{name}: is not an integer"),
+            Self::WrongType(name, VarType::Bool) => write!(f, "{name}: is not a boolean"),
+            Self::WrongType(name, VarType::Url) => write!(f, "{name}: is not a URL"),
+            Self::WrongType(name, kind) => write!(f, "{name}: is not a valid {kind:?}"),
+            Self::NotInEnum(name, values) => {
+                write!(f, "{name}: is not one of {}", values.join(", "))
+            }
+            Self::NoMatch(name, pattern) => write!(f, "{name}: does not match /{pattern}/"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SchemaError {
+    #[error("cannot read the schema file")]
+    Io(#[source] std::io::Error),
+    #[error("the schema file is invalid")]
+    Parse(#[source] toml::de::Error),
+    #[error("variable '{0}' in the schema has an invalid pattern")]
+    Pattern(String, #[source] regex::Error),
+    #[error("variable '{0}' in the schema is an enum without values")]
+    EmptyEnum(String),
+    #[error(
+        "the environment does not match the schema:{}",
+        .0.iter().map(|v| format!("\n  - {v}")).collect::<String>()
+    )]
+    Invalid(Vec<Violation>),
+}
+
+pub type Result<T, E = SchemaError> = std::result::Result<T, E>;
+
+fn is_bool(value: &str) -> bool {
+    ["true", "false", "1", "0", "yes", "no"]
+        .iter()
+        .any(|b| b.eq_ignore_ascii_case(value))
+}
+
+fn is_url(value: &str) -> bool {
+    let (scheme, rest) = match value.split_once("://") {
+        Some(parts) => parts,
+        None => return false,
+    };
+    let mut chars = scheme.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "+.-".contains(c))
+        && !rest.is_empty()
+        && !rest.starts_with('/')
+        && !rest.contains(char::is_whitespace)
+}
+
+impl VarSpec {
+    fn check(&self, name: &str, value: &str) -> Result<Option<Violation>> {
+        let valid = match self.kind {
+            VarType::String => true,
+            VarType::Int => value.parse::<i64>().is_ok(),
+            VarType::Bool => is_bool(value),
+            VarType::Url => is_url(value),
+            VarType::Enum => {
+                if !self.values.iter().any(|v| v == value) {
+                    return Ok(Some(Violation::NotInEnum(
+                        name.to_string(),
+                        self.values.clone(),
+                    )));
+                }
+                true
+            }
+        };
+        if !valid {
+            return Ok(Some(Violation::WrongType(name.to_string(), self.kind)));
+        }
+        if let Some(pattern) = &self.pattern {
+            let re = Regex::new(pattern).map_err(|e| SchemaError::Pattern(name.to_string(), e))?;
+            if !re.is_match(value) {
+                return Ok(Some(Violation::NoMatch(name.to_string(), pattern.clone())));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Schema {
+    pub fn parse(raw: &str) -> Result<Self> {
+        let schema: Self = toml::from_str(raw).map_err(SchemaError::Parse)?;
+        for (name, spec) in &schema.0 {
+            if spec.kind == VarType::Enum && spec.values.is_empty() {
+                return Err(SchemaError::EmptyEnum(name.clone()));
+            }
+            if let Some(pattern) = &spec.pattern {
+                Regex::new(pattern).map_err(|e| SchemaError::Pattern(name.clone(), e))?;
+            }
+        }
+        Ok(schema)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path).map_err(SchemaError::Io)?)
+    }
+
+    /// Adds variables that have to be defined, but can have any value. Variables already in the
+    /// schema keep their type, but are no longer optional.
+    pub fn require(&mut self, names: &[String]) {
+        for name in names {
+            self.0.entry(name.clone()).or_default().optional = false;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Checks all the variables and reports all the problems at once.
+    pub fn validate(&self, env: &HashMap<String, String>) -> Result<()> {
+        let mut violations = vec![];
+        for (name, spec) in &self.0 {
+            match env.get(name) {
+                Some(value) => violations.extend(spec.check(name, value)?),
+                None if spec.optional => {}
+                None => violations.push(Violation::Missing(name.clone())),
+            }
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(SchemaError::Invalid(violations))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! env {
+        ($a:expr, $b:expr) => {
+            ($a.to_string(), $b.to_string())
+        };
+    }
+
+    const SCHEMA: &str = r#"
+        [DATABASE_URL]
+        type = "url"
+
+        [PORT]
+        type = "int"
+
+        [DEBUG]
+        type = "bool"
+        optional = true
+
+        [LOG_LEVEL]
+        type = "enum"
+        values = ["debug", "info"]
+
+        [APP_NAME]
+        pattern = "^[a-z]+$"
+    "#;
+
+    #[test]
+    fn validates_env() {
+        let schema = Schema::parse(SCHEMA).unwrap();
+        let env = HashMap::from([
+            env!("DATABASE_URL", "postgres://user:pass@db:5432/app"),
+            env!("PORT", "8080"),
+            env!("LOG_LEVEL", "info"),
+            env!("APP_NAME", "app"),
+        ]);
+        schema.validate(&env).unwrap();
+
+        let env = HashMap::from([
+            env!("DATABASE_URL", "db:5432"),
+            env!("PORT", "80a"),
+            env!("DEBUG", "maybe"),
+            env!("LOG_LEVEL", "trace"),
+        ]);
+        let err = schema.validate(&env).unwrap_err();
+        assert_eq!(
+            "the environment does not match the schema:\n  \
+             - APP_NAME: is not defined\n  \
+             - DATABASE_URL: is not a URL\n  \
+             - DEBUG: is not a boolean\n  \
+             - LOG_LEVEL: is not one of debug, info\n  \
+             - PORT: is not an integer",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn requires_variables() {
+        let mut schema = Schema::parse(SCHEMA).unwrap();
+        schema.require(&["PORT".to_string(), "OTHER".to_string(), "DEBUG".to_string()]);
+
+        let env = HashMap::from([
+            env!("DATABASE_URL", "https://example.com"),
+            env!("PORT", "x"),
+            env!("LOG_LEVEL", "debug"),
+            env!("APP_NAME", "App"),
+        ]);
+        assert!(matches!(
+            schema.validate(&env),
+            Err(SchemaError::Invalid(v)) if v == vec![
+                Violation::NoMatch("APP_NAME".to_string(), "^[a-z]+$".to_string()),
+                Violation::Missing("DEBUG".to_string()),
+                Violation::Missing("OTHER".to_string()),
+                Violation::WrongType("PORT".to_string(), VarType::Int),
+            ]
+        ));
+    }
+
+    #[test]
+    fn fails_on_invalid_schema() {
+        assert!(matches!(
+            Schema::parse("[A]\ntype = \"enum\""),
+            Err(SchemaError::EmptyEnum(_))
+        ));
+        assert!(matches!(
+            Schema::parse("[A]\npattern = \"(\""),
+            Err(SchemaError::Pattern(..))
+        ));
+        assert!(matches!(
+            Schema::parse("[A]\ntype = \"float\""),
+            Err(SchemaError::Parse(_))
+        ));
+        assert!(matches!(
+            Schema::parse("[A]\nunknown = 1"),
+            Err(SchemaError::Parse(_))
+        ));
+    }
+}
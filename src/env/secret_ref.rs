@@ -0,0 +1,271 @@
+use anyhow::Result;
+use serde_json::Value;
+use thiserror::Error;
+
+use super::{convert::value_as_string, Secrets, Vault};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provider {
+    Aws,
+    Azure,
+    Google,
+    Vault,
+}
+
+/// A reference to a single secret, e.g. `azkv://keyvault/secret#key`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SecretRef {
+    pub provider: Provider,
+    /// The Key Vault name (Azure), the project (Google) or the mount (Hashicorp Vault).
+    pub location: Option<String>,
+    pub secret: String,
+    pub version: Option<String>,
+    /// The key of the JSON document stored in the secret.
+    pub key: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum SecretRefError {
+    #[error("the secret reference in variable '{0}' is invalid")]
+    Invalid(String),
+    #[error("cannot resolve the secret reference in variable '{0}'")]
+    Resolve(String, #[source] anyhow::Error),
+    #[error("the secret referenced in variable '{0}' is not a JSON object")]
+    NotAnObject(String),
+    #[error("the secret referenced in variable '{0}' does not have key '{1}'")]
+    MissingKey(String, String),
+}
+
+fn split_location(path: &str) -> Option<(&str, &str)> {
+    path.split_once('/')
+        .filter(|(l, s)| !l.is_empty() && !s.is_empty())
+}
+
+fn parse_path(provider: Provider, path: &str) -> Option<(Option<String>, String, Option<String>)> {
+    match provider {
+        Provider::Aws => (!path.is_empty()).then(|| (None, path.to_string(), None)),
+        Provider::Vault => split_location(path)
+            .map(|(mount, secret)| (Some(mount.to_string()), secret.to_string(), None)),
+        Provider::Azure => {
+            let (vault, rest) = split_location(path)?;
+            let (secret, version) = match rest.split_once('/') {
+                Some((s, v)) if !v.is_empty() && !v.contains('/') => (s, Some(v.to_string())),
+                Some(_) => return None,
+                None => (rest, None),
+            };
+            Some((Some(vault.to_string()), secret.to_string(), version))
+        }
+        Provider::Google => {
+            let (project, rest) = split_location(path)?;
+            let (secret, version) = match rest.split_once("/versions/") {
+                Some((s, v)) if !v.is_empty() && !v.contains('/') => (s, Some(v.to_string())),
+                Some(_) => return None,
+                None => (rest, None),
+            };
+            (!secret.contains('/'))
+                .then(|| (Some(project.to_string()), secret.to_string(), version))
+        }
+    }
+}
+
+const SCHEMES: [(&str, Provider); 3] = [
+    ("azkv://", Provider::Azure),
+    ("gcpsm://", Provider::Google),
+    ("vault://", Provider::Vault),
+];
+
+/// Parses the value of an environment variable as a secret reference. Returns `None` if the value
+/// does not look like a reference at all.
+pub fn parse(name: &str, value: &str) -> Option<Result<SecretRef, SecretRefError>> {
+    let (provider, path) = if let Some(rest) = value.strip_prefix("kvenv://") {
+        let (provider, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let provider = match provider {
+            "aws" => Provider::Aws,
+            "azure" => Provider::Azure,
+            "google" => Provider::Google,
+            "vault" => Provider::Vault,
+            _ => return Some(Err(SecretRefError::Invalid(name.to_string()))),
+        };
+        (provider, path)
+    } else {
+        SCHEMES
+            .iter()
+            .find_map(|(scheme, p)| value.strip_prefix(scheme).map(|path| (*p, path)))?
+    };
+
+    let (path, key) = match path.split_once('#') {
+        Some((_, "")) => return Some(Err(SecretRefError::Invalid(name.to_string()))),
+        Some((p, k)) => (p, Some(k.to_string())),
+        None => (path, None),
+    };
+    let parsed = parse_path(provider, path).map(|(location, secret, version)| SecretRef {
+        provider,
+        location,
+        secret,
+        version,
+        key,
+    });
+    Some(parsed.ok_or_else(|| SecretRefError::Invalid(name.to_string())))
+}
+
+fn resolve(name: &str, r: &SecretRef, vault: &dyn Vault, secrets: &mut Secrets) -> Result<()> {
+    let raw = vault
+        .download_raw(&r.secret, r.version.as_deref())
+        .map_err(|e| SecretRefError::Resolve(name.to_string(), e))?;
+    match &r.key {
+        Some(key) => {
+            let mut doc = match serde_json::from_slice(&raw) {
+                Ok(Value::Object(doc)) => doc,
+                _ => return Err(SecretRefError::NotAnObject(name.to_string()).into()),
+            };
+            let value = doc
+                .remove(key)
+                .ok_or_else(|| SecretRefError::MissingKey(name.to_string(), key.clone()))?;
+            secrets.push_text(name.to_string(), value_as_string(name, value)?);
+        }
+        None => match String::from_utf8(raw) {
+            Ok(value) => secrets.push_text(name.to_string(), value),
+            Err(e) => secrets.push_binary(name.to_string(), e.as_bytes()),
+        },
+    }
+    Ok(())
+}
+
+/// Finds secret references in `env` and resolves them, using vaults created by `get_vault`.
+pub fn resolve_refs<I, F>(env: I, get_vault: F) -> Result<Secrets>
+where
+    I: IntoIterator<Item = (String, String)>,
+    F: Fn(&SecretRef) -> Result<Box<dyn Vault>>,
+{
+    let mut secrets = Secrets::default();
+    for (name, value) in env {
+        if let Some(r) = parse(&name, &value) {
+            let r = r?;
+            let vault = get_vault(&r).map_err(|e| SecretRefError::Resolve(name.clone(), e))?;
+            resolve(&name, &r, vault.as_ref(), &mut secrets)?;
+        }
+    }
+    Ok(secrets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::env::tests::MemoryVault;
+
+    macro_rules! secret_ref {
+        ($provider:ident, $location:expr, $secret:expr, $version:expr, $key:expr) => {
+            SecretRef {
+                provider: Provider::$provider,
+                location: $location.map(|x: &str| x.to_string()),
+                secret: $secret.to_string(),
+                version: $version.map(|x: &str| x.to_string()),
+                key: $key.map(|x: &str| x.to_string()),
+            }
+        };
+    }
+
+    #[test]
+    fn parses_references() {
+        macro_rules! assert_parse {
+            ($a:expr, $b:expr) => {
+                assert_eq!($b, parse("VAR", $a).unwrap().unwrap());
+            };
+        }
+
+        assert_parse!(
+            "kvenv://aws/prod/db#password",
+            secret_ref!(Aws, None, "prod/db", None, Some("password"))
+        );
+        assert_parse!(
+            "kvenv://azure/vault/secret",
+            secret_ref!(Azure, Some("vault"), "secret", None, None)
+        );
+        assert_parse!(
+            "azkv://vault/secret",
+            secret_ref!(Azure, Some("vault"), "secret", None, None)
+        );
+        assert_parse!(
+            "azkv://vault/secret/abcd#key",
+            secret_ref!(Azure, Some("vault"), "secret", Some("abcd"), Some("key"))
+        );
+        assert_parse!(
+            "gcpsm://project/secret",
+            secret_ref!(Google, Some("project"), "secret", None, None)
+        );
+        assert_parse!(
+            "gcpsm://project/secret/versions/3",
+            secret_ref!(Google, Some("project"), "secret", Some("3"), None)
+        );
+        assert_parse!(
+            "vault://secret/app/db#key",
+            secret_ref!(Vault, Some("secret"), "app/db", None, Some("key"))
+        );
+    }
+
+    #[test]
+    fn ignores_regular_values() {
+        assert!(parse("VAR", "value").is_none());
+        assert!(parse("VAR", "https://example.com").is_none());
+        assert!(parse("VAR", "").is_none());
+    }
+
+    #[test]
+    fn fails_on_invalid_references() {
+        macro_rules! assert_fail {
+            ($a:expr) => {
+                assert!(parse("VAR", $a).unwrap().is_err());
+            };
+        }
+
+        assert_fail!("kvenv://");
+        assert_fail!("kvenv://unknown/secret");
+        assert_fail!("kvenv://aws/");
+        assert_fail!("kvenv://aws/secret#");
+        assert_fail!("azkv://vault");
+        assert_fail!("azkv://vault/");
+        assert_fail!("azkv://vault/secret/version/extra");
+        assert_fail!("gcpsm://project/secret/versions/");
+        assert_fail!("gcpsm://project/secret/other");
+        assert_fail!("vault://mount");
+    }
+
+    #[test]
+    fn resolves_references() {
+        let get_vault = |_: &SecretRef| -> Result<Box<dyn Vault>> {
+            Ok(Box::new(MemoryVault::new([
+                ("plain", b"value".to_vec()),
+                ("json", br#"{"key": 10}"#.to_vec()),
+                ("binary", vec![0xff, 0x00]),
+            ])))
+        };
+        let env = vec![
+            ("A".to_string(), "kvenv://aws/plain".to_string()),
+            ("B".to_string(), "kvenv://aws/json#key".to_string()),
+            ("C".to_string(), "kvenv://aws/binary".to_string()),
+            ("D".to_string(), "not a reference".to_string()),
+        ];
+
+        let secrets = resolve_refs(env, get_vault).unwrap();
+
+        assert_eq!(
+            Secrets {
+                vars: vec![
+                    ("A".to_string(), "value".to_string()),
+                    ("B".to_string(), "10".to_string()),
+                    ("C".to_string(), "/wA=".to_string()),
+                ],
+                binary: vec!["C".to_string()],
+            },
+            secrets
+        );
+
+        let missing = vec![("A".to_string(), "kvenv://aws/json#other".to_string())];
+        assert!(resolve_refs(missing, get_vault).is_err());
+        let not_json = vec![("A".to_string(), "kvenv://aws/plain#key".to_string())];
+        assert!(resolve_refs(not_json, get_vault).is_err());
+        let not_found = vec![("A".to_string(), "kvenv://aws/other".to_string())];
+        assert!(resolve_refs(not_found, get_vault).is_err());
+    }
+}
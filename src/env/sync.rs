@@ -0,0 +1,305 @@
+use anyhow::{anyhow, bail, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::PathBuf,
+};
+
+use crate::cache;
+
+use super::{
+    convert::{encode_env_to_dotenv, DecodeOptions},
+    plan_upload, read_env_file,
+    secret_ref::{self, Provider},
+    SecretSelector, Secrets, StoreConfig, Vault,
+};
+
+/// Where `sync` reads the variables from or writes them to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// A JSON secret or secrets with a prefix in a secret store.
+    Store {
+        provider: Provider,
+        /// The Key Vault name (Azure), the project (Google) or the mount (Hashicorp Vault).
+        location: Option<String>,
+        selector: SecretSelector,
+    },
+    /// A dotenv, JSON, YAML or TOML file.
+    File(PathBuf),
+}
+
+/// Parses a location written like a secret reference (e.g. `azkv://keyvault/app`), where a
+/// trailing `*` selects the secrets with the prefix (e.g. `azkv://keyvault/app-*`). Anything that
+/// is not a secret reference is a path to a file.
+pub fn parse_location(s: &str) -> Result<Location> {
+    let Some(r) = secret_ref::parse("", s) else {
+        return Ok(Location::File(PathBuf::from(s)));
+    };
+    let r = r.map_err(|_| anyhow!("'{}' is not a valid secret store location", s))?;
+    if r.version.is_some() || r.key.is_some() {
+        bail!("location '{}' cannot have a version or a key", s);
+    }
+    let selector = match r.secret.strip_suffix('*') {
+        Some(prefix) => SecretSelector::Prefix(prefix.to_string()),
+        None => SecretSelector::Name(r.secret),
+    };
+    Ok(Location::Store {
+        provider: r.provider,
+        location: r.location,
+        selector,
+    })
+}
+
+impl Location {
+    fn vault(&self, store: &StoreConfig) -> Result<Option<Box<dyn Vault>>> {
+        match self {
+            Self::Store {
+                provider, location, ..
+            } => Ok(Some(store.ref_vault(*provider, location.as_deref())?)),
+            Self::File(_) => Ok(None),
+        }
+    }
+}
+
+fn text_vars(secrets: Secrets) -> Result<Vec<(String, String)>> {
+    if !secrets.binary.is_empty() {
+        bail!(
+            "binary secrets cannot be synced: {}",
+            secrets.binary.join(", ")
+        );
+    }
+    Ok(secrets.vars)
+}
+
+/// The variables read from a location.
+#[derive(Debug, Default)]
+pub struct LocationEnv {
+    pub vars: Vec<(String, String)>,
+    /// The secrets holding the variables in prefixed mode, which are not always named after them
+    /// (e.g. `app-db-host` holds `db_host` in AWS, a Hashicorp Vault document holds many).
+    secrets: HashMap<String, String>,
+}
+
+impl From<Vec<(String, String)>> for LocationEnv {
+    fn from(vars: Vec<(String, String)>) -> Self {
+        Self {
+            vars,
+            secrets: HashMap::new(),
+        }
+    }
+}
+
+/// Reads the variables from the location. With `missing_ok`, a secret or a file that does not
+/// exist is read as an empty environment.
+pub fn read_location(
+    store: &StoreConfig,
+    location: &Location,
+    missing_ok: bool,
+) -> Result<LocationEnv> {
+    match (location, location.vault(store)?) {
+        (Location::File(path), _) => {
+            if missing_ok && !path.exists() {
+                return Ok(LocationEnv::default());
+            }
+            Ok(read_env_file(path)?.into())
+        }
+        (Location::Store { selector, .. }, Some(vault)) => {
+            read_store(vault.as_ref(), selector, missing_ok)
+        }
+        (Location::Store { .. }, None) => unreachable!("stores always have a vault"),
+    }
+}
+
+fn read_store(
+    vault: &dyn Vault,
+    selector: &SecretSelector,
+    missing_ok: bool,
+) -> Result<LocationEnv> {
+    let opts = DecodeOptions::default();
+    match selector {
+        SecretSelector::Prefix(prefix) => {
+            let mut secrets = HashMap::new();
+            for listed in vault.list_prefixed(prefix, &opts)? {
+                for var in listed.vars.unwrap_or_default() {
+                    secrets.insert(var, listed.name.clone());
+                }
+            }
+            let vars = text_vars(vault.download_prefixed(prefix, &opts)?)?;
+            Ok(LocationEnv { vars, secrets })
+        }
+        SecretSelector::Name(name) => {
+            if missing_ok && !vault.secret_exists(name)? {
+                return Ok(LocationEnv::default());
+            }
+            Ok(text_vars(vault.download_json(name, &opts)?)?.into())
+        }
+    }
+}
+
+/// Applies the changes to the location, whose current contents are `current`: `set` adds or
+/// replaces variables and `delete` removes them.
+pub fn write_location(
+    store: &StoreConfig,
+    location: &Location,
+    current: LocationEnv,
+    set: Vec<(String, String)>,
+    delete: &[String],
+) -> Result<()> {
+    match (location, location.vault(store)?) {
+        (Location::File(path), _) => {
+            let mut vars = Secrets::from(current.vars);
+            for name in delete {
+                vars.remove(name);
+            }
+            vars.extend(set.into());
+            let is_json = path.extension().is_some_and(|e| e == "json");
+            let contents = if is_json {
+                let doc: serde_json::Map<_, _> = vars
+                    .vars
+                    .into_iter()
+                    .map(|(k, v)| (k, serde_json::Value::String(v)))
+                    .collect();
+                serde_json::to_string_pretty(&doc)? + "\n"
+            } else {
+                encode_env_to_dotenv(&vars.vars)
+            };
+            cache::write_private_file(path, contents.as_bytes())?;
+        }
+        (Location::Store { selector, .. }, Some(vault)) => {
+            write_store(vault.as_ref(), selector, current, set, delete)?;
+        }
+        (Location::Store { .. }, None) => unreachable!("stores always have a vault"),
+    }
+    Ok(())
+}
+
+fn write_store(
+    vault: &dyn Vault,
+    selector: &SecretSelector,
+    current: LocationEnv,
+    set: Vec<(String, String)>,
+    delete: &[String],
+) -> Result<()> {
+    let SecretSelector::Prefix(prefix) = selector else {
+        let mut vars = Secrets::from(current.vars);
+        for name in delete {
+            vars.remove(name);
+        }
+        vars.extend(set.into());
+        for (secret, value, _) in plan_upload(vault, selector, vars.vars, false)? {
+            vault.upload_raw(&secret, &value)?;
+        }
+        return Ok(());
+    };
+
+    // Variables stay in the secrets they were read from, new ones get their own secrets
+    let secret_of = |name: &str| match current.secrets.get(name) {
+        Some(secret) => secret.clone(),
+        None => vault.prefixed_secret(prefix, name, "").0,
+    };
+    let mut contents: BTreeMap<String, Secrets> = BTreeMap::new();
+    for (name, value) in current.vars.iter().cloned() {
+        contents
+            .entry(secret_of(&name))
+            .or_default()
+            .push_text(name, value);
+    }
+    let mut changed = BTreeSet::new();
+    for name in delete {
+        let secret = secret_of(name);
+        contents.entry(secret.clone()).or_default().remove(name);
+        changed.insert(secret);
+    }
+    for (name, value) in set {
+        let secret = secret_of(&name);
+        contents
+            .entry(secret.clone())
+            .or_default()
+            .push_text(name, value);
+        changed.insert(secret);
+    }
+    for secret in changed {
+        let vars = contents.remove(&secret).unwrap_or_default().vars;
+        if vars.is_empty() {
+            vault.delete_secret(&secret)?;
+        } else {
+            vault.upload_raw(&secret, &vault.prefixed_value(vars)?)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::env::tests::MemoryVault;
+
+    #[test]
+    fn parses_locations() {
+        assert_eq!(
+            Location::Store {
+                provider: Provider::Azure,
+                location: Some("keyvault".to_string()),
+                selector: SecretSelector::Name("app".to_string()),
+            },
+            parse_location("azkv://keyvault/app").unwrap()
+        );
+        assert_eq!(
+            Location::Store {
+                provider: Provider::Vault,
+                location: Some("secret".to_string()),
+                selector: SecretSelector::Prefix("app/".to_string()),
+            },
+            parse_location("kvenv://vault/secret/app/*").unwrap()
+        );
+        assert_eq!(
+            Location::Store {
+                provider: Provider::Aws,
+                location: None,
+                selector: SecretSelector::Prefix("prod-".to_string()),
+            },
+            parse_location("kvenv://aws/prod-*").unwrap()
+        );
+        assert_eq!(
+            Location::File(PathBuf::from("./prod.env")),
+            parse_location("./prod.env").unwrap()
+        );
+        assert!(parse_location("kvenv://unknown/app").is_err());
+        assert!(parse_location("azkv://keyvault/app#key").is_err());
+    }
+
+    #[test]
+    fn writes_to_the_secrets_read_with_prefix() {
+        let vault = MemoryVault::new([
+            ("app-db-host", "old"),
+            ("app-db-port", "5432"),
+            ("app-old-var", "x"),
+        ]);
+        let selector = SecretSelector::Prefix("app-".to_string());
+        let vars = |vars: &[(&str, &str)]| -> Vec<(String, String)> {
+            vars.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+
+        let current = read_store(&vault, &selector, true).unwrap();
+        assert_eq!(
+            vars(&[("db_host", "old"), ("db_port", "5432"), ("old_var", "x")]),
+            current.vars
+        );
+        let set = vars(&[("db_host", "new"), ("new_var", "y")]);
+        write_store(&vault, &selector, current, set, &["old_var".to_string()]).unwrap();
+
+        let secrets: Vec<_> = vault
+            .list_prefixed("app-", &DecodeOptions::default())
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(vec!["app-db-host", "app-db-port", "app-new_var"], secrets);
+        assert_eq!(
+            vars(&[("db_host", "new"), ("db_port", "5432"), ("new_var", "y")]),
+            read_store(&vault, &selector, false).unwrap().vars
+        );
+    }
+}
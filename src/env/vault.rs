@@ -1,15 +1,24 @@
-use std::{collections::HashMap, path::PathBuf};
+use std::{path::PathBuf, time::Duration};
 
-use clap::{arg, command, ArgGroup, Args};
+use clap::{ArgGroup, Args};
 use futures::future::try_join_all;
 use reqwest::{self, StatusCode};
 use serde::Deserialize;
+use serde_json::{Map, Value};
 use thiserror::Error;
 use tokio::io::AsyncReadExt;
+use tracing::{debug, instrument, warn};
 
-use super::{convert::as_valid_env_name, Vault, VaultConfig};
+use crate::audit::{self, AuditError};
+use crate::logging;
+use crate::retry::{self, Verdict};
 
-#[derive(Args, Debug)]
+use super::{
+    convert::{decode_env_from_json, DecodeOptions},
+    ListedSecret, Secrets, Vault, VaultConfig,
+};
+
+#[derive(Args, Clone, Debug)]
 #[command(group = ArgGroup::new("hashicorp"))]
 pub struct HashicorpVaultConfig {
     /// Use Hashicorp Vault.
@@ -59,8 +68,8 @@ pub enum HashicorpVaultError {
     #[error("HTTP error occurred")]
     HttpError(#[source] reqwest::Error),
 
-    #[error("the Vault returned non-200 error code")]
-    HttpStatusCodeError(StatusCode),
+    #[error("the Vault returned non-200 error code {0}")]
+    HttpStatusCodeError(StatusCode, Option<Duration>),
 
     #[error("cannot deserialize the response")]
     DeserializeError(#[source] reqwest::Error),
@@ -70,14 +79,23 @@ pub enum HashicorpVaultError {
 
     #[error("the configuration is invalid")]
     ConfigurationError(#[from] anyhow::Error),
+
+    #[error("secret '{0}' has to be a JSON object")]
+    NotAnObject(String),
+
+    #[error(transparent)]
+    AuditError(#[from] AuditError),
 }
 
 pub struct HashicorpVault {
     address: String,
     token: String,
     cacert: Option<PathBuf>,
+    mount: String,
 }
 
+const DEFAULT_MOUNT: &str = "secret";
+
 impl VaultConfig for HashicorpVaultConfig {
     type Vault = HashicorpVault;
 
@@ -90,15 +108,37 @@ impl VaultConfig for HashicorpVaultConfig {
             address: self.vault_address.unwrap(),
             token: self.vault_token.unwrap(),
             cacert: self.vault_cacert,
+            mount: DEFAULT_MOUNT.to_string(),
         })
     }
+
+    fn ref_vault(&self, location: Option<&str>) -> anyhow::Result<Self::Vault> {
+        let (Some(address), Some(token)) = (&self.vault_address, &self.vault_token) else {
+            return Err(HashicorpVaultError::ConfigurationError(anyhow::Error::msg(
+                "Vault address and token are required to resolve references",
+            ))
+            .into());
+        };
+        Ok(Self::Vault {
+            address: address.clone(),
+            token: token.clone(),
+            cacert: self.vault_cacert.clone(),
+            mount: location.unwrap_or(DEFAULT_MOUNT).to_string(),
+        })
+    }
+
+    fn credential_vars(&self) -> &'static [&'static str] {
+        &["VAULT_TOKEN"]
+    }
 }
 
 impl HashicorpVault {
+    #[instrument(name = "credential", level = "debug", skip_all, fields(address = self.address))]
     async fn client(&self) -> Result<reqwest::Client, HashicorpVaultError> {
         let mut builder = reqwest::Client::builder().user_agent("kvenv");
 
         if let Some(path) = self.cacert.as_ref() {
+            debug!(cacert = %path.display(), "using the CA certificate");
             let mut buffer = Vec::new();
             {
                 let mut file = tokio::fs::File::open(path)
@@ -116,80 +156,300 @@ impl HashicorpVault {
             .build()
             .map_err(anyhow::Error::new)
             .map_err(HashicorpVaultError::ConfigurationError)
+            .inspect_err(|e| {
+                warn!(
+                    error = e as &dyn std::error::Error,
+                    "cannot set up the client"
+                )
+            })
     }
 
-    fn parse_secrets(secret: SecretResponse) -> Result<Vec<(String, String)>, HashicorpVaultError> {
-        secret
-            .data
+    fn parse_secrets(
+        secret_name: &str,
+        secret: SecretResponse,
+        opts: &DecodeOptions,
+    ) -> Result<Vec<(String, String)>, HashicorpVaultError> {
+        let vars = decode_env_from_json(secret_name, Value::Object(secret.data.data), opts)
+            .map_err(HashicorpVaultError::InvalidEnv)?;
+        logging::redact_values(&vars);
+        Ok(vars)
+    }
+
+    #[instrument(level = "debug", skip(self, client), fields(mount = self.mount))]
+    async fn get_secret(
+        &self,
+        client: &reqwest::Client,
+        secret_name: &str,
+        version: Option<&str>,
+    ) -> Result<SecretResponse, HashicorpVaultError> {
+        let request = || async move {
+            let mut request = client
+                .get(format!(
+                    "{}/v1/{}/data/{}",
+                    self.address, self.mount, secret_name
+                ))
+                .header("X-Vault-Token", &self.token);
+            if let Some(version) = version {
+                request = request.query(&[("version", version)]);
+            }
+            let response = request
+                .send()
+                .await
+                .map_err(HashicorpVaultError::HttpError)?;
+            handle_common_errors(secret_name, &response)?;
+            response
+                .json::<SecretResponse>()
+                .await
+                .map_err(HashicorpVaultError::DeserializeError)
+        };
+        let secret = retry::retry(request, verdict).await.inspect_err(|e| {
+            warn!(
+                secret = secret_name,
+                error = e as &dyn std::error::Error,
+                "cannot read the secret"
+            )
+        })?;
+        let version = secret.data.metadata.as_ref().map(|m| m.version.to_string());
+        debug!(version, "read the secret");
+        audit::secret_read(
+            "vault",
+            Some(&format!("{}/{}", self.address, self.mount)),
+            secret_name,
+            version.as_deref(),
+        )?;
+        Ok(secret)
+    }
+
+    /// Names of the secrets starting with the prefix, in alphabetical order.
+    #[instrument(level = "debug", skip(self, client), fields(mount = self.mount))]
+    async fn list_names(
+        &self,
+        client: &reqwest::Client,
+        prefix: &str,
+    ) -> Result<Vec<String>, HashicorpVaultError> {
+        let request = || async move {
+            let response = client
+                .get(format!(
+                    "{}/v1/{}/metadata?list=true",
+                    self.address, self.mount
+                ))
+                .header("X-Vault-Token", &self.token)
+                .send()
+                .await
+                .map_err(HashicorpVaultError::HttpError)?;
+            // Listing an empty mount is reported as not found
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(ListResponse::default());
+            }
+            handle_common_errors(prefix, &response)?;
+            response
+                .json::<ListResponse>()
+                .await
+                .map_err(HashicorpVaultError::DeserializeError)
+        };
+        let list = retry::retry(request, verdict).await.inspect_err(|e| {
+            warn!(
+                prefix,
+                error = e as &dyn std::error::Error,
+                "cannot list the secrets"
+            )
+        })?;
+
+        let mut names: Vec<_> = list
             .data
+            .keys
             .into_iter()
-            .map(|(k, v)| as_valid_env_name(k).map(|k| (k, v)))
-            .collect::<anyhow::Result<Vec<_>>>()
-            .map_err(HashicorpVaultError::InvalidEnv)
+            .filter(|p| p.starts_with(prefix))
+            .collect();
+        names.sort();
+        debug!(count = names.len(), "listed the secrets");
+        Ok(names)
     }
 
     async fn get_single_key(
         &self,
         client: &reqwest::Client,
         secret_name: impl AsRef<str>,
+        opts: &DecodeOptions,
     ) -> Result<Vec<(String, String)>, HashicorpVaultError> {
-        let response = client
-            .get(format!(
-                "{}/v1/secret/data/{}",
-                self.address,
-                secret_name.as_ref()
-            ))
-            .header("X-Vault-Token", &self.token)
-            .send()
-            .await
-            .map_err(HashicorpVaultError::HttpError)?;
-        handle_common_errors(secret_name.as_ref(), &response)?;
-
-        let data: SecretResponse = response
-            .json()
-            .await
-            .map_err(HashicorpVaultError::DeserializeError)?;
-        Self::parse_secrets(data)
+        let data = self.get_secret(client, secret_name.as_ref(), None).await?;
+        Self::parse_secrets(secret_name.as_ref(), data, opts)
     }
 }
 
 impl Vault for HashicorpVault {
+    #[instrument(skip(self, opts), fields(address = self.address))]
     #[tokio::main]
-    async fn download_prefixed(&self, prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let client = self.client().await?;
-
-        let response = client
-            .get(format!("{}/v1/secret/metadata?list=true", self.address))
-            .header("X-Vault-Token", &self.token)
-            .send()
-            .await
-            .map_err(HashicorpVaultError::HttpError)?;
-        handle_common_errors(prefix, &response)?;
-
-        let list: ListResponse = response
-            .json()
-            .await
-            .map_err(HashicorpVaultError::DeserializeError)?;
-
-        let env_values = list
-            .data
-            .keys
-            .into_iter()
-            .filter(|p| p.starts_with(prefix))
-            .map(|s| self.get_single_key(&client, s));
-        let env_values: Vec<_> = try_join_all(env_values)
-            .await?
-            .into_iter()
-            .flatten()
-            .collect();
-        Ok(env_values)
+    async fn download_prefixed(
+        &self,
+        prefix: &str,
+        opts: &DecodeOptions,
+    ) -> anyhow::Result<Secrets> {
+        retry::timeout(async {
+            let client = self.client().await?;
+
+            let env_values = self
+                .list_names(&client, prefix)
+                .await?
+                .into_iter()
+                .map(|s| self.get_single_key(&client, s, opts));
+            let env_values: Vec<_> = try_join_all(env_values)
+                .await?
+                .into_iter()
+                .flatten()
+                .collect();
+            Ok(env_values.into())
+        })
+        .await
+    }
+
+    #[instrument(skip(self, opts), fields(address = self.address))]
+    #[tokio::main]
+    async fn list_prefixed(
+        &self,
+        prefix: &str,
+        opts: &DecodeOptions,
+    ) -> anyhow::Result<Vec<ListedSecret>> {
+        retry::timeout(async {
+            let client = self.client().await?;
+
+            let names = self.list_names(&client, prefix).await?;
+            let client = &client;
+            let vars = names.iter().map(|s| async move {
+                let vars = self.get_single_key(client, s, opts).await?;
+                Ok(vars.into_iter().map(|(k, _)| k).collect())
+            });
+            let vars = futures::future::join_all(vars).await;
+            Ok(names
+                .into_iter()
+                .zip(vars)
+                .map(|(name, vars)| ListedSecret::new(name, vars))
+                .collect())
+        })
+        .await
     }
 
+    #[instrument(skip(self, opts), fields(address = self.address))]
     #[tokio::main]
-    async fn download_json(&self, secret_name: &str) -> anyhow::Result<Vec<(String, String)>> {
-        let client = self.client().await?;
-        let result = self.get_single_key(&client, secret_name).await?;
-        Ok(result)
+    async fn download_json(
+        &self,
+        secret_name: &str,
+        opts: &DecodeOptions,
+    ) -> anyhow::Result<Secrets> {
+        retry::timeout(async {
+            let client = self.client().await?;
+            let result = self.get_single_key(&client, secret_name, opts).await?;
+            Ok(result.into())
+        })
+        .await
+    }
+
+    #[instrument(skip(self), fields(address = self.address))]
+    #[tokio::main]
+    async fn download_raw(
+        &self,
+        secret_name: &str,
+        version: Option<&str>,
+    ) -> anyhow::Result<Vec<u8>> {
+        retry::timeout(async {
+            let client = self.client().await?;
+            let secret = self.get_secret(&client, secret_name, version).await?;
+            Ok(serde_json::to_vec(&secret.data.data)?)
+        })
+        .await
+    }
+
+    #[tokio::main]
+    async fn upload_raw(&self, secret_name: &str, value: &str) -> anyhow::Result<()> {
+        let data: Map<String, Value> = serde_json::from_str(value)
+            .map_err(|_| HashicorpVaultError::NotAnObject(secret_name.to_string()))?;
+        let body = serde_json::json!({ "data": data });
+        retry::timeout(async {
+            let client = self.client().await?;
+            let request = || async {
+                let response = client
+                    .post(format!(
+                        "{}/v1/{}/data/{}",
+                        self.address, self.mount, secret_name
+                    ))
+                    .header("X-Vault-Token", &self.token)
+                    .json(&body)
+                    .send()
+                    .await
+                    .map_err(HashicorpVaultError::HttpError)?;
+                if response.status() != StatusCode::NO_CONTENT {
+                    handle_common_errors(secret_name, &response)?;
+                }
+                Ok(())
+            };
+            Ok(retry::retry(request, verdict).await?)
+        })
+        .await
+    }
+
+    #[tokio::main]
+    async fn delete_secret(&self, secret_name: &str) -> anyhow::Result<()> {
+        retry::timeout(async {
+            let client = self.client().await?;
+            // Deleting the metadata removes all the versions of the secret
+            let request = || async {
+                let response = client
+                    .delete(format!(
+                        "{}/v1/{}/metadata/{}",
+                        self.address, self.mount, secret_name
+                    ))
+                    .header("X-Vault-Token", &self.token)
+                    .send()
+                    .await
+                    .map_err(HashicorpVaultError::HttpError)?;
+                if response.status() != StatusCode::NO_CONTENT {
+                    handle_common_errors(secret_name, &response)?;
+                }
+                Ok(())
+            };
+            Ok(retry::retry(request, verdict).await?)
+        })
+        .await
+    }
+
+    #[tokio::main]
+    async fn secret_exists(&self, secret_name: &str) -> anyhow::Result<bool> {
+        retry::timeout(async {
+            let client = self.client().await?;
+            // Listing is not recursive, so nested secrets are looked up directly
+            let request = || async {
+                let response = client
+                    .get(format!(
+                        "{}/v1/{}/metadata/{}",
+                        self.address, self.mount, secret_name
+                    ))
+                    .header("X-Vault-Token", &self.token)
+                    .send()
+                    .await
+                    .map_err(HashicorpVaultError::HttpError)?;
+                match handle_common_errors(secret_name, &response) {
+                    Ok(()) => Ok(true),
+                    Err(HashicorpVaultError::SecretNotFound(_)) => Ok(false),
+                    Err(e) => Err(e),
+                }
+            };
+            Ok(retry::retry(request, verdict).await?)
+        })
+        .await
+    }
+
+    fn prefixed_secret(&self, prefix: &str, name: &str, value: &str) -> (String, String) {
+        // Every secret is a document, `download_prefixed` uses its keys as variable names
+        let doc = serde_json::json!({ name: value });
+        (format!("{prefix}{name}"), doc.to_string())
+    }
+
+    fn prefixed_value(&self, vars: Vec<(String, String)>) -> anyhow::Result<String> {
+        let doc: Map<_, _> = vars
+            .into_iter()
+            .map(|(k, v)| (k, Value::String(v)))
+            .collect();
+        Ok(Value::Object(doc).to_string())
     }
 }
 
@@ -202,7 +462,28 @@ fn handle_common_errors(
         StatusCode::UNAUTHORIZED => Err(HashicorpVaultError::UnauthorizedError),
         StatusCode::FORBIDDEN => Err(HashicorpVaultError::ForbiddenError(secret_name.to_string())),
         StatusCode::OK => Ok(()),
-        other => Err(HashicorpVaultError::HttpStatusCodeError(other)),
+        other => {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(retry::parse_retry_after);
+            Err(HashicorpVaultError::HttpStatusCodeError(other, retry_after))
+        }
+    }
+}
+
+/// Rate-limited requests (with `rate_limit` quotas), server errors and failed connections are
+/// retried.
+fn verdict(error: &HashicorpVaultError) -> Verdict {
+    match error {
+        HashicorpVaultError::HttpError(e) if e.is_connect() || e.is_timeout() => {
+            Verdict::Retry(None)
+        }
+        HashicorpVaultError::HttpStatusCodeError(status, retry_after) => {
+            retry::status_verdict(status.as_u16(), *retry_after)
+        }
+        _ => Verdict::Fail,
     }
 }
 
@@ -213,15 +494,22 @@ struct SecretResponse {
 
 #[derive(Deserialize, Debug)]
 struct Secret {
-    pub data: HashMap<String, String>,
+    pub data: Map<String, Value>,
+    #[serde(default)]
+    pub metadata: Option<SecretMetadata>,
 }
 
 #[derive(Deserialize, Debug)]
+struct SecretMetadata {
+    pub version: u64,
+}
+
+#[derive(Deserialize, Debug, Default)]
 struct ListResponse {
     pub data: KeyList,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 struct KeyList {
     pub keys: Vec<String>,
 }
@@ -248,8 +536,9 @@ mod tests {
         let mut proc_env = cfg
             .into_vault()
             .unwrap()
-            .download_json("prefixed-1")
-            .unwrap();
+            .download_json("prefixed-1", &DecodeOptions::default())
+            .unwrap()
+            .vars;
         proc_env.sort();
         assert_eq!(
             vec![
@@ -271,8 +560,9 @@ mod tests {
         let mut proc_env = cfg
             .into_vault()
             .unwrap()
-            .download_prefixed("prefixed-")
-            .unwrap();
+            .download_prefixed("prefixed-", &DecodeOptions::default())
+            .unwrap()
+            .vars;
         proc_env.sort();
         assert_eq!(
             vec![
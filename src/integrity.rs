@@ -0,0 +1,129 @@
+use anyhow::Result;
+use clap::{ArgGroup, Args, ValueHint};
+use std::{
+    fs,
+    io::Read,
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+use crate::agent::{self, SocketConfig};
+
+#[derive(Error, Debug)]
+pub enum IntegrityError {
+    #[error("cannot read the integrity key from '{0}'")]
+    Read(PathBuf, #[source] std::io::Error),
+    #[error("refusing to use the integrity key from '{0}' - it must be owned by you and not accessible by others (mode {1:o}, expected 600)")]
+    Permissions(PathBuf, u32),
+    #[error("the integrity key is empty")]
+    Empty,
+}
+
+/// The variables holding the integrity key, which are never passed to commands nor cached.
+pub const KEY_VARS: [&str; 2] = ["KVENV_INTEGRITY_KEY", "KVENV_INTEGRITY_KEY_FILE"];
+
+/// The key authenticating the cached env files. When set, `cache` adds an HMAC of the contents to
+/// the file and commands loading the file refuse files that were modified (or cached without the
+/// key).
+#[derive(Args, Clone, Debug)]
+#[command(group = ArgGroup::new("integrity").multiple(false))]
+pub struct IntegrityConfig {
+    /// Path to the file with the integrity key (any bytes). The file must not be accessible by
+    /// other users.
+    #[arg(
+        long,
+        env = "KVENV_INTEGRITY_KEY_FILE",
+        value_parser,
+        value_hint = ValueHint::FilePath,
+        group = "integrity",
+        global = true
+    )]
+    integrity_key_file: Option<PathBuf>,
+
+    /// The integrity key itself. Prefer the environment variable, as the arguments of processes
+    /// are visible to other users.
+    #[arg(
+        long,
+        env = "KVENV_INTEGRITY_KEY",
+        hide_env_values = true,
+        group = "integrity",
+        global = true
+    )]
+    integrity_key: Option<String>,
+
+    /// Use the integrity key of the running `kvenv agent`. The agent generates a new key when it
+    /// starts, so files cached before it was restarted are refused.
+    #[arg(long, group = "integrity", global = true)]
+    integrity_key_from_agent: bool,
+}
+
+fn read_key_file(path: &Path) -> Result<Vec<u8>, IntegrityError> {
+    let read_error = |e| IntegrityError::Read(path.to_owned(), e);
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .map_err(read_error)?;
+    let meta = file.metadata().map_err(read_error)?;
+    let mode = meta.mode() & 0o777;
+    if mode & 0o077 != 0 || meta.uid() != unsafe { libc::getuid() } {
+        return Err(IntegrityError::Permissions(path.to_owned(), mode));
+    }
+    let mut key = vec![];
+    file.read_to_end(&mut key).map_err(read_error)?;
+    Ok(key)
+}
+
+impl IntegrityConfig {
+    /// The configured key, if any. `socket` is used to reach the agent.
+    pub fn key(&self, socket: &SocketConfig) -> Result<Option<Vec<u8>>> {
+        let key = if let Some(path) = &self.integrity_key_file {
+            read_key_file(path)?
+        } else if let Some(key) = &self.integrity_key {
+            key.as_bytes().to_vec()
+        } else if self.integrity_key_from_agent {
+            agent::integrity_key(socket)?
+        } else {
+            return Ok(None);
+        };
+        if key.is_empty() {
+            return Err(IntegrityError::Empty.into());
+        }
+        Ok(Some(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn reads_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.key");
+        fs::write(&path, b"file key").unwrap();
+        let socket = SocketConfig::default();
+        let cfg = |file: Option<PathBuf>, key: Option<&str>| IntegrityConfig {
+            integrity_key_file: file,
+            integrity_key: key.map(String::from),
+            integrity_key_from_agent: false,
+        };
+
+        assert_eq!(None, cfg(None, None).key(&socket).unwrap());
+        assert_eq!(
+            Some(b"env key".to_vec()),
+            cfg(None, Some("env key")).key(&socket).unwrap()
+        );
+        assert!(cfg(None, Some("")).key(&socket).is_err());
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(cfg(Some(path.clone()), None).key(&socket).is_err());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(
+            Some(b"file key".to_vec()),
+            cfg(Some(path), None).key(&socket).unwrap()
+        );
+    }
+}
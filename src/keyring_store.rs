@@ -0,0 +1,228 @@
+use anyhow::Result;
+use clap::{Args, ValueEnum};
+use keyring::{keyutils::KeyutilsCredential, secret_service::SsCredential, Credential, Entry};
+use rand::{distributions::Alphanumeric, Rng};
+use thiserror::Error;
+#[cfg(any(test, feature = "keyring-file"))]
+use {
+    clap::ValueHint,
+    keyring::credential::CredentialApi,
+    std::{any::Any, fs, io, os::unix::fs::DirBuilderExt, path::PathBuf},
+};
+
+use crate::env::{CacheFileError, ProcessEnv};
+
+/// The service name of all the entries created by `kvenv`.
+const SERVICE: &str = "kvenv";
+
+#[derive(Error, Debug)]
+pub enum KeyringError {
+    #[error("cannot access the keyring")]
+    Access(#[from] keyring::Error),
+    #[error("the keyring does not have environment '{0}'")]
+    NotFound(String),
+    #[error("cannot serialize the environment")]
+    Serialization(#[from] serde_json::Error),
+    #[error("the environment in the keyring is invalid")]
+    Invalid(#[from] CacheFileError),
+}
+
+/// The keyring where the environments are stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum KeyringBackend {
+    /// The Secret Service of the desktop session (e.g. GNOME Keyring or KWallet).
+    #[default]
+    SecretService,
+    /// The kernel keyring, which works without a desktop session (e.g. over SSH). The
+    /// environments are kept in memory and are lost on reboot.
+    Keyutils,
+    /// Files readable only by the current user in `keyring-dir`, for machines without a keyring
+    /// (e.g. CI). The environments are not encrypted.
+    #[cfg(any(test, feature = "keyring-file"))]
+    File,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct KeyringConfig {
+    /// The keyring used by `cache --store keyring` and `run-with --handle`.
+    #[arg(
+        long,
+        value_enum,
+        env = "KVENV_KEYRING_BACKEND",
+        default_value_t,
+        global = true
+    )]
+    keyring_backend: KeyringBackend,
+
+    /// The directory of the `file` keyring. Defaults to `$XDG_RUNTIME_DIR/kvenv/keyring`, or
+    /// `/tmp/kvenv-$UID/keyring` if `XDG_RUNTIME_DIR` is not set.
+    #[cfg(any(test, feature = "keyring-file"))]
+    #[arg(
+        long,
+        env = "KVENV_KEYRING_DIR",
+        value_parser,
+        value_hint = ValueHint::DirPath,
+        global = true
+    )]
+    keyring_dir: Option<PathBuf>,
+}
+
+impl KeyringConfig {
+    fn entry(&self, handle: &str) -> Result<Entry, KeyringError> {
+        let credential: Box<Credential> = match self.keyring_backend {
+            KeyringBackend::SecretService => {
+                Box::new(SsCredential::new_with_target(None, SERVICE, handle)?)
+            }
+            KeyringBackend::Keyutils => {
+                Box::new(KeyutilsCredential::new_with_target(None, SERVICE, handle)?)
+            }
+            #[cfg(any(test, feature = "keyring-file"))]
+            KeyringBackend::File => Box::new(self.file_credential(handle)?),
+        };
+        Ok(Entry::new_with_credential(credential))
+    }
+
+    #[cfg(any(test, feature = "keyring-file"))]
+    fn file_credential(&self, handle: &str) -> Result<FileCredential, KeyringError> {
+        if handle.is_empty() || handle.contains('/') || handle.starts_with('.') {
+            return Err(KeyringError::NotFound(handle.to_string()));
+        }
+        let dir = match (&self.keyring_dir, std::env::var_os("XDG_RUNTIME_DIR")) {
+            (Some(dir), _) => dir.clone(),
+            (None, Some(runtime)) => PathBuf::from(runtime).join("kvenv").join("keyring"),
+            (None, None) => std::env::temp_dir()
+                .join(format!("kvenv-{}", unsafe { libc::getuid() }))
+                .join("keyring"),
+        };
+        Ok(FileCredential(dir.join(handle)))
+    }
+}
+
+/// An entry of the `file` keyring, stored like the cache files.
+#[cfg(any(test, feature = "keyring-file"))]
+#[derive(Debug)]
+struct FileCredential(PathBuf);
+
+#[cfg(any(test, feature = "keyring-file"))]
+fn file_error(e: io::Error) -> keyring::Error {
+    match e.kind() {
+        io::ErrorKind::NotFound => keyring::Error::NoEntry,
+        _ => keyring::Error::PlatformFailure(Box::new(e)),
+    }
+}
+
+#[cfg(any(test, feature = "keyring-file"))]
+impl CredentialApi for FileCredential {
+    fn set_secret(&self, secret: &[u8]) -> keyring::Result<()> {
+        if let Some(dir) = self.0.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)
+                .map_err(file_error)?;
+        }
+        crate::cache::write_private_file(&self.0, secret)
+            .map_err(|e| keyring::Error::PlatformFailure(e.into()))
+    }
+
+    fn get_secret(&self) -> keyring::Result<Vec<u8>> {
+        fs::read(&self.0).map_err(file_error)
+    }
+
+    fn delete_credential(&self) -> keyring::Result<()> {
+        fs::remove_file(&self.0).map_err(file_error)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn new_handle() -> String {
+    let suffix: String = rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(10)
+        .map(|c| char::from(c).to_ascii_lowercase())
+        .collect();
+    format!("kvenv-{suffix}")
+}
+
+fn not_found(handle: &str) -> impl FnOnce(keyring::Error) -> KeyringError + '_ {
+    move |e| match e {
+        keyring::Error::NoEntry => KeyringError::NotFound(handle.to_string()),
+        e => KeyringError::Access(e),
+    }
+}
+
+/// Stores the environment in the keyring under a new handle and returns the handle. The environment
+/// is stored in the format of the cache files, `source` describes where it comes from.
+pub fn store_env(cfg: &KeyringConfig, env: &ProcessEnv, source: &str) -> Result<String> {
+    let handle = new_handle();
+    let mut data = vec![];
+    env.to_writer(&mut data, source, None)
+        .map_err(KeyringError::Serialization)?;
+    cfg.entry(&handle)?
+        .set_secret(&data)
+        .map_err(KeyringError::Access)?;
+    Ok(handle)
+}
+
+/// Loads the environment stored in the keyring under the handle.
+pub fn load_env(cfg: &KeyringConfig, handle: &str) -> Result<ProcessEnv> {
+    let data = cfg.entry(handle)?.get_secret().map_err(not_found(handle))?;
+    Ok(ProcessEnv::from_reader(data.as_slice(), None).map_err(KeyringError::Invalid)?)
+}
+
+/// Removes the environment stored under the handle from the keyring.
+pub fn delete_env(cfg: &KeyringConfig, handle: &str) -> Result<()> {
+    cfg.entry(handle)?
+        .delete_credential()
+        .map_err(not_found(handle))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_handles() {
+        let handle = new_handle();
+        assert!(handle.starts_with("kvenv-"));
+        assert_eq!(16, handle.len());
+        assert!(handle[6..]
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit()));
+        assert_ne!(handle, new_handle());
+    }
+
+    #[test]
+    fn stores_environments_in_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let cfg = KeyringConfig {
+            keyring_backend: KeyringBackend::File,
+            keyring_dir: Some(dir.path().join("keyring")),
+        };
+        let env = ProcessEnv::fresh(
+            vec![],
+            vec![("KV".to_string(), "secret".to_string())],
+            vec![],
+        );
+
+        let handle = store_env(&cfg, &env, "test").unwrap();
+        assert_eq!(env.secrets(), load_env(&cfg, &handle).unwrap().secrets());
+        let mode = fs::metadata(dir.path().join("keyring").join(&handle))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(0o600, mode & 0o777);
+
+        delete_env(&cfg, &handle).unwrap();
+        let not_found = |e: anyhow::Error| matches!(e.downcast(), Ok(KeyringError::NotFound(_)));
+        assert!(not_found(load_env(&cfg, &handle).unwrap_err()));
+        assert!(not_found(delete_env(&cfg, &handle).unwrap_err()));
+        assert!(not_found(load_env(&cfg, "../keyring").unwrap_err()));
+    }
+}
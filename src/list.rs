@@ -0,0 +1,73 @@
+use anyhow::Result;
+use clap::Args;
+use thiserror::Error;
+
+use crate::env::{list_secrets, EnvConfig, ListedSecret};
+
+#[derive(Error, Debug)]
+pub enum ListError {
+    #[error("cannot list secrets")]
+    Load(#[source] anyhow::Error),
+}
+
+/// Lists the secrets and the variables they produce, without printing any values. Exits with
+/// status 1 if any secret cannot be used.
+#[derive(Args, Debug)]
+#[command(visible_alias = "inspect")]
+pub struct List {
+    #[command(flatten)]
+    env: EnvConfig,
+}
+
+fn render(secrets: &[ListedSecret]) -> String {
+    let mut out = String::new();
+    for secret in secrets {
+        let line = match &secret.vars {
+            Ok(vars) => format!("{}: {}", secret.name, vars.join(", ")),
+            Err(e) => format!("{}: error: {}", secret.name, e),
+        };
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+pub fn run_list(cfg: List) -> Result<std::convert::Infallible> {
+    let secrets = list_secrets(cfg.env).map_err(ListError::Load)?;
+    print!("{}", render(&secrets));
+    std::process::exit(if secrets.iter().all(|s| s.vars.is_ok()) {
+        0
+    } else {
+        1
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_secrets() {
+        let secrets = vec![
+            ListedSecret {
+                name: "app-db-pass".to_string(),
+                vars: Ok(vec!["DB_PASS".to_string()]),
+            },
+            ListedSecret {
+                name: "app/db".to_string(),
+                vars: Ok(vec!["DB_USER".to_string(), "DB_PASS".to_string()]),
+            },
+            ListedSecret {
+                name: "app-1st".to_string(),
+                vars: Err("secret name '1st' is invalid".to_string()),
+            },
+        ];
+
+        assert_eq!(
+            "app-db-pass: DB_PASS\n\
+             app/db: DB_USER, DB_PASS\n\
+             app-1st: error: secret name '1st' is invalid\n",
+            render(&secrets)
+        );
+    }
+}
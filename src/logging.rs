@@ -0,0 +1,146 @@
+use clap::{ArgAction, Args, ValueEnum};
+use std::{
+    io::{self, IsTerminal, Write},
+    mem,
+    sync::Mutex,
+};
+use tracing_subscriber::EnvFilter;
+
+use crate::redact::Redactor;
+
+/// The format of the log written to stderr.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Args, Debug)]
+pub struct LogConfig {
+    /// Log what `kvenv` is doing to stderr: `-v` for the progress, `-vv` for every request to the
+    /// secret store and `-vvv` for the logs of the libraries as well. `KVENV_LOG` (e.g.
+    /// `kvenv=debug,reqwest=trace`) overrides the levels.
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// The format of the log.
+    #[arg(
+        long,
+        value_enum,
+        env = "KVENV_LOG_FORMAT",
+        default_value_t,
+        global = true
+    )]
+    log_format: LogFormat,
+}
+
+fn default_filter(verbose: u8) -> &'static str {
+    match verbose {
+        0 => "warn",
+        1 => "warn,kvenv=info",
+        2 => "warn,kvenv=debug",
+        _ => "debug,kvenv=trace",
+    }
+}
+
+/// The values of the secrets read so far, see `redact_values`.
+static REDACTOR: Mutex<Option<Redactor>> = Mutex::new(None);
+
+/// Redacts the values of the secrets from all the following log records. The spans and events
+/// never include the values, this makes sure that they do not leak through the errors of the
+/// libraries either.
+pub fn redact_values(secrets: &[(String, String)]) {
+    add_values(secrets.iter().map(|(_, v)| v.as_bytes()));
+}
+
+/// Redacts a single value from all the following log records. The backends call it as soon as they
+/// read a secret, before the other requests of the same download can fail and be logged.
+pub fn redact_value(value: &[u8]) {
+    add_values([value]);
+}
+
+fn add_values<'a>(values: impl IntoIterator<Item = &'a [u8]>) {
+    REDACTOR
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| Redactor::new([]))
+        .add(values);
+}
+
+fn redacted(record: Vec<u8>) -> Vec<u8> {
+    match REDACTOR.lock().unwrap().as_ref() {
+        Some(redactor) => redactor.redact_all(&record),
+        None => record,
+    }
+}
+
+/// Collects a single log record and writes it to stderr, redacted, when dropped.
+#[derive(Default)]
+struct RedactingWriter {
+    record: Vec<u8>,
+}
+
+impl Write for RedactingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.record.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for RedactingWriter {
+    fn drop(&mut self) {
+        let record = redacted(mem::take(&mut self.record));
+        // There is nowhere to report the failure to write the log
+        let _ = io::stderr().write_all(&record);
+    }
+}
+
+/// Sets up the log for the rest of the process.
+pub fn init(cfg: &LogConfig) {
+    let filter = EnvFilter::try_from_env("KVENV_LOG")
+        .unwrap_or_else(|_| EnvFilter::new(default_filter(cfg.verbose)));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(RedactingWriter::default);
+    match cfg.log_format {
+        LogFormat::Text => builder.with_ansi(io::stderr().is_terminal()).init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether the value is in the record. Other tests add their values to the same redactor, so the
+    /// records are not compared as a whole.
+    fn contains(record: &[u8], value: &str) -> bool {
+        record.windows(value.len()).any(|w| w == value.as_bytes())
+    }
+
+    #[test]
+    fn redacts_secret_values() {
+        let record = b"cannot parse 'Jq7vX2pLw9-s3cr3t' as JSON".to_vec();
+        assert!(contains(&redacted(record.clone()), "Jq7vX2pLw9-s3cr3t"));
+
+        redact_values(&[("KV".to_string(), "Jq7vX2pLw9-s3cr3t".to_string())]);
+        assert!(!contains(&redacted(record), "Jq7vX2pLw9"));
+    }
+
+    #[test]
+    fn redacts_values_as_they_are_read() {
+        let record = b"request failed: token=Hb4nT8kYz1-t0k3n".to_vec();
+        assert!(contains(&redacted(record.clone()), "Hb4nT8kYz1-t0k3n"));
+
+        let mut secrets = crate::env::Secrets::default();
+        secrets.push_text("TOKEN".to_string(), "Hb4nT8kYz1-t0k3n".to_string());
+        assert!(!contains(&redacted(record), "Hb4nT8kYz1"));
+    }
+}
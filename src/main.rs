@@ -1,17 +1,37 @@
 use anyhow::Result;
-use clap::{command, Parser, Subcommand};
+use clap::{Parser, Subcommand};
 
+mod agent;
+mod audit;
 mod cache;
+mod diff;
 mod env;
+mod integrity;
+mod keyring_store;
+mod list;
+mod logging;
+mod redact;
+mod retry;
 mod run;
 mod run_in;
 mod run_with;
+mod sync;
+mod upload;
 
 #[derive(Parser, Debug)]
 #[command(name = "kvenv", about, version, author, next_line_help = true)]
 struct Cli {
     #[command(subcommand)]
     command: Command,
+
+    #[command(flatten)]
+    audit: audit::AuditConfig,
+
+    #[command(flatten)]
+    log: logging::LogConfig,
+
+    #[command(flatten)]
+    retry: retry::RetryConfig,
 }
 
 #[derive(Subcommand, Debug)]
@@ -19,10 +39,37 @@ enum Command {
     Cache(cache::Cache),
     RunWith(run_with::RunWith),
     RunIn(run_in::RunIn),
+    Diff(diff::Diff),
+    List(list::List),
+    Set(upload::Set),
+    Import(upload::Import),
+    Push(upload::Push),
+    Sync(sync::Sync),
+    Agent(agent::Agent),
+}
+
+impl Command {
+    fn name(&self) -> &'static str {
+        match self {
+            Command::Cache(_) => "cache",
+            Command::RunWith(_) => "run-with",
+            Command::RunIn(_) => "run-in",
+            Command::Diff(_) => "diff",
+            Command::List(_) => "list",
+            Command::Set(_) => "set",
+            Command::Import(_) => "import",
+            Command::Push(_) => "push",
+            Command::Sync(_) => "sync",
+            Command::Agent(_) => "agent",
+        }
+    }
 }
 
 fn main() -> Result<()> {
     let opts: Cli = Cli::parse();
+    logging::init(&opts.log);
+    retry::init(&opts.retry);
+    audit::init(&opts.audit, opts.command.name())?;
     match opts.command {
         Command::Cache(c) => {
             cache::run_cache(c)?;
@@ -33,6 +80,27 @@ fn main() -> Result<()> {
         Command::RunIn(c) => {
             run_in::run_in(c)?;
         }
+        Command::Diff(c) => {
+            diff::run_diff(c)?;
+        }
+        Command::List(c) => {
+            list::run_list(c)?;
+        }
+        Command::Set(c) => {
+            upload::run_set(c)?;
+        }
+        Command::Import(c) => {
+            upload::run_import(c)?;
+        }
+        Command::Push(c) => {
+            upload::run_push(c)?;
+        }
+        Command::Sync(c) => {
+            sync::run_sync(c)?;
+        }
+        Command::Agent(c) => {
+            agent::run_agent(c)?;
+        }
     }
     Ok(())
 }
@@ -48,6 +116,15 @@ mod tests {
         assert_correct(&["kvenv", "cache", "--help"]);
         assert_correct(&["kvenv", "run-in", "--help"]);
         assert_correct(&["kvenv", "run-with", "--help"]);
+        assert_correct(&["kvenv", "diff", "--help"]);
+        assert_correct(&["kvenv", "list", "--help"]);
+        assert_correct(&["kvenv", "inspect", "--help"]);
+        assert_correct(&["kvenv", "set", "--help"]);
+        assert_correct(&["kvenv", "import", "--help"]);
+        assert_correct(&["kvenv", "push", "--help"]);
+        assert_correct(&["kvenv", "sync", "--help"]);
+        assert_correct(&["kvenv", "agent", "--help"]);
+        assert_correct(&["kvenv", "agent", "flush", "--help"]);
     }
 
     fn assert_correct(args: &[&str]) {
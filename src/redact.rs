@@ -0,0 +1,244 @@
+use base64::{engine::general_purpose::STANDARD_NO_PAD as base64, Engine as _};
+use clap::ValueEnum;
+use std::io::{self, ErrorKind, Read, Write};
+
+const REDACTED: &[u8] = b"***";
+
+/// Values shorter than this are not redacted, as they would make the output unreadable.
+const MIN_LEN: usize = 4;
+
+/// Replaces secret values (and their base64 and URL-encoded forms) in the output of a command.
+#[derive(Debug)]
+pub struct Redactor {
+    /// The values to redact, the longest first.
+    patterns: Vec<Vec<u8>>,
+}
+
+fn url_encode(value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.len());
+    for b in value {
+        if b.is_ascii_alphanumeric() || b"-_.~".contains(b) {
+            out.push(*b);
+        } else {
+            out.extend(format!("%{b:02X}").bytes());
+        }
+    }
+    out
+}
+
+impl Redactor {
+    pub fn new<'a>(values: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        let mut redactor = Self { patterns: vec![] };
+        redactor.add(values);
+        redactor
+    }
+
+    /// Adds more values to redact.
+    pub fn add<'a>(&mut self, values: impl IntoIterator<Item = &'a [u8]>) {
+        let patterns = &mut self.patterns;
+        for value in values.into_iter().filter(|v| v.len() >= MIN_LEN) {
+            patterns.push(value.to_vec());
+            // Without padding, so that the value is found in padded and unpadded forms
+            patterns.push(base64.encode(value).into_bytes());
+            patterns.push(url_encode(value));
+        }
+        patterns.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+        patterns.dedup();
+    }
+
+    fn is_partial_match(&self, rest: &[u8]) -> bool {
+        self.patterns
+            .iter()
+            .any(|p| p.len() > rest.len() && p.starts_with(rest))
+    }
+
+    fn full_match(&self, rest: &[u8]) -> Option<usize> {
+        self.patterns
+            .iter()
+            .find(|p| rest.starts_with(p))
+            .map(Vec::len)
+    }
+
+    /// Redacts the pending data. Unless `eof` is set, the data that might be the beginning of a
+    /// value is kept for the next call, so that values split between reads are still redacted.
+    fn redact(&self, pending: &mut Vec<u8>, eof: bool) -> Vec<u8> {
+        let mut out = Vec::with_capacity(pending.len());
+        let mut i = 0;
+        while i < pending.len() {
+            let rest = &pending[i..];
+            if !eof && self.is_partial_match(rest) {
+                break;
+            }
+            match self.full_match(rest) {
+                Some(len) => {
+                    out.extend(REDACTED);
+                    i += len;
+                }
+                None => {
+                    out.push(rest[0]);
+                    i += 1;
+                }
+            }
+        }
+        pending.drain(..i);
+        out
+    }
+
+    /// Redacts the values in the complete `data`.
+    pub fn redact_all(&self, data: &[u8]) -> Vec<u8> {
+        self.redact(&mut data.to_vec(), true)
+    }
+
+    /// Copies the data from `from` to `to`, redacting the values. The data is written as soon as
+    /// it is read, except for the parts that might be the beginning of a value.
+    pub fn forward(&self, mut from: impl Read, mut to: impl Write) -> io::Result<()> {
+        let mut buf = [0; 8192];
+        let mut pending = vec![];
+        loop {
+            let n = match from.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            pending.extend_from_slice(&buf[..n]);
+            to.write_all(&self.redact(&mut pending, false))?;
+            to.flush()?;
+        }
+        to.write_all(&self.redact(&mut pending, true))?;
+        to.flush()
+    }
+}
+
+/// The CI platform that is asked to mask the values in its logs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CiMask {
+    /// The platform is detected from the variables it sets (`GITHUB_ACTIONS`, `TF_BUILD`). GitLab CI
+    /// is not detected, as it needs the output to be redacted.
+    #[default]
+    Auto,
+    /// The values are not masked.
+    None,
+    /// GitHub Actions, using `::add-mask::` commands.
+    Github,
+    /// GitLab CI. It cannot mask values at runtime, so the output is redacted instead (see
+    /// `redact-output`), which means the command does not run in a terminal.
+    Gitlab,
+    /// Azure DevOps, using `##vso[task.setsecret]` commands.
+    AzureDevops,
+}
+
+impl CiMask {
+    /// Resolves `Auto` to the detected platform, `var` reads the OS environment.
+    pub fn resolve(self, var: impl Fn(&str) -> Option<String>) -> Self {
+        let is_set = |name, value: &str| var(name).is_some_and(|v| v.eq_ignore_ascii_case(value));
+        match self {
+            Self::Auto if is_set("GITHUB_ACTIONS", "true") => Self::Github,
+            Self::Auto if is_set("TF_BUILD", "true") => Self::AzureDevops,
+            Self::Auto => Self::None,
+            other => other,
+        }
+    }
+
+    /// The commands that make the platform mask the values. Every line of a multiline value is
+    /// masked separately, as the platforms match single lines.
+    pub fn commands<'a>(self, values: impl IntoIterator<Item = &'a str>) -> String {
+        let mut out = String::new();
+        let lines = values
+            .into_iter()
+            .flat_map(str::lines)
+            .filter(|l| l.len() >= MIN_LEN);
+        for line in lines {
+            match self {
+                Self::Github => {
+                    out.push_str(&format!("::add-mask::{}\n", line.replace('%', "%25")))
+                }
+                Self::AzureDevops => out.push_str(&format!(
+                    "##vso[task.setsecret]{}\n",
+                    line.replace('%', "%AZP25")
+                )),
+                Self::Auto | Self::None | Self::Gitlab => {}
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redact(redactor: &Redactor, chunks: &[&str]) -> String {
+        let mut pending = vec![];
+        let mut out = vec![];
+        for chunk in chunks {
+            pending.extend(chunk.as_bytes());
+            out.extend(redactor.redact(&mut pending, false));
+        }
+        out.extend(redactor.redact(&mut pending, true));
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn redacts_values() {
+        let redactor = Redactor::new([&b"p@ss word"[..], b"secrets", b"abc", b""]);
+
+        assert_eq!(
+            "db=*** b64=*** url=*** short=abc",
+            redact(
+                &redactor,
+                &["db=p@ss word b64=cEBzcyB3b3Jk url=p%40ss%20word short=abc"]
+            )
+        );
+        assert_eq!("a ***==\n", redact(&redactor, &["a c2VjcmV0cw==\n"]));
+        assert_eq!(
+            "x *** y sec",
+            redact(&redactor, &["x se", "c", "rets y s", "ec"])
+        );
+    }
+
+    #[test]
+    fn detects_ci() {
+        let env = |name: &'static str, value: &'static str| {
+            move |n: &str| (n == name).then(|| value.to_string())
+        };
+        assert_eq!(
+            CiMask::Github,
+            CiMask::Auto.resolve(env("GITHUB_ACTIONS", "true"))
+        );
+        assert_eq!(CiMask::None, CiMask::Auto.resolve(env("GITLAB_CI", "true")));
+        assert_eq!(
+            CiMask::AzureDevops,
+            CiMask::Auto.resolve(env("TF_BUILD", "True"))
+        );
+        assert_eq!(CiMask::None, CiMask::Auto.resolve(env("CI", "true")));
+        assert_eq!(
+            CiMask::Github,
+            CiMask::Github.resolve(env("TF_BUILD", "True"))
+        );
+        assert_eq!(
+            CiMask::None,
+            CiMask::None.resolve(env("GITHUB_ACTIONS", "true"))
+        );
+    }
+
+    #[test]
+    fn emits_mask_commands() {
+        let values = ["100%", "multi\r\nline value", "abc"];
+        assert_eq!(
+            "::add-mask::100%25\n::add-mask::multi\n::add-mask::line value\n",
+            CiMask::Github.commands(values)
+        );
+        assert_eq!(
+            "##vso[task.setsecret]100%AZP25\n##vso[task.setsecret]multi\n##vso[task.setsecret]line value\n",
+            CiMask::AzureDevops.commands(values)
+        );
+        assert_eq!("", CiMask::Gitlab.commands(values));
+    }
+
+    #[test]
+    fn prefers_longest_value() {
+        let redactor = Redactor::new([&b"token"[..], b"token-2"]);
+        assert_eq!("*** ***", redact(&redactor, &["token-2 tok", "en"]));
+    }
+}
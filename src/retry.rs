@@ -0,0 +1,248 @@
+use anyhow::Result;
+use clap::Args;
+use rand::Rng;
+use std::{
+    future::Future,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant, SystemTime},
+};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::run::parse_duration;
+
+/// The longest delay between two attempts, also when the server asks for a longer one.
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum RetryError {
+    #[error("the secret store did not respond within {}", humantime::format_duration(*.0))]
+    Timeout(Duration),
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct RetryConfig {
+    /// How many times a failed request to the secret store is retried. Only throttled requests
+    /// (e.g. HTTP 429), server errors and connection failures are retried.
+    #[arg(long, env = "KVENV_RETRIES", default_value_t = 3, global = true)]
+    retries: u32,
+
+    /// The delay before the first retry. It doubles with every retry and is randomized by up to a
+    /// half. A delay requested by the server with `Retry-After` takes precedence.
+    #[arg(
+        long,
+        env = "KVENV_RETRY_BACKOFF",
+        value_parser = parse_duration,
+        default_value = "500ms",
+        global = true
+    )]
+    retry_backoff: Duration,
+
+    /// The maximum time of all the requests of the command to the secret store, including the
+    /// retries. With `--watch`, every download of the environment has the whole time.
+    #[arg(long, env = "KVENV_TIMEOUT", value_parser = parse_duration, global = true)]
+    timeout: Option<Duration>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            retry_backoff: Duration::from_millis(500),
+            timeout: None,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// The delay before the `retry`-th retry (counted from 0).
+    fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(delay) = retry_after {
+            return delay.min(MAX_DELAY);
+        }
+        let delay = self
+            .retry_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(MAX_DELAY);
+        // Half of the delay is random, so that parallel requests are not retried all at once
+        delay / 2 + rand::thread_rng().gen_range(Duration::ZERO..=delay / 2)
+    }
+}
+
+static CONFIG: OnceLock<RetryConfig> = OnceLock::new();
+
+/// When the requests to the secret store must be done by, with the `--timeout` it was set from.
+static DEADLINE: Mutex<Option<(Instant, Duration)>> = Mutex::new(None);
+
+/// Sets the retry policy for the rest of the process and starts the `--timeout`.
+pub fn init(cfg: &RetryConfig) {
+    let _ = CONFIG.set(cfg.clone());
+    restart_deadline();
+}
+
+/// Gives the following requests the whole `--timeout` again, e.g. for every download in watch
+/// mode.
+pub fn restart_deadline() {
+    *DEADLINE.lock().unwrap() = config()
+        .timeout
+        .map(|limit| (Instant::now() + limit, limit));
+}
+
+fn config() -> RetryConfig {
+    CONFIG.get().cloned().unwrap_or_default()
+}
+
+/// What to do after a failed request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The request will fail again, e.g. the secret does not exist.
+    Fail,
+    /// The request may succeed later, optionally after the delay requested by the server.
+    Retry(Option<Duration>),
+}
+
+/// Throttled requests and server errors are retried.
+pub fn status_verdict(status: u16, retry_after: Option<Duration>) -> Verdict {
+    match status {
+        408 | 429 | 500 | 502 | 503 | 504 => Verdict::Retry(retry_after),
+        _ => Verdict::Fail,
+    }
+}
+
+/// Parses the value of the `Retry-After` header, either seconds or an HTTP date.
+#[allow(dead_code)]
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
+
+/// Sends the `request` until it succeeds, fails with an error that the `verdict` does not retry,
+/// or runs out of retries.
+pub async fn retry<T, E, F, Fut>(mut request: F, verdict: impl Fn(&E) -> Verdict) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: std::error::Error + 'static,
+{
+    let cfg = config();
+    let mut retry = 0;
+    loop {
+        let error = match request().await {
+            Err(e) if retry < cfg.retries => e,
+            result => return result,
+        };
+        let Verdict::Retry(retry_after) = verdict(&error) else {
+            return Err(error);
+        };
+        let delay = cfg.delay(retry, retry_after);
+        retry += 1;
+        warn!(
+            error = &error as &dyn std::error::Error,
+            retry,
+            delay = %humantime::format_duration(delay),
+            "request failed, retrying"
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Runs the `operation` on the secret store, failing if it does not finish before the deadline set
+/// by `--timeout`, which all the operations of the command share.
+pub async fn timeout<T>(operation: impl Future<Output = Result<T>>) -> Result<T> {
+    let deadline = *DEADLINE.lock().unwrap();
+    match deadline {
+        Some((deadline, limit)) => tokio::time::timeout_at(deadline.into(), operation)
+            .await
+            .map_err(|_| RetryError::Timeout(limit))?,
+        None => operation.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{cell::Cell, io};
+
+    #[test]
+    fn parses_retry_after() {
+        assert_eq!(Some(Duration::from_secs(5)), parse_retry_after("5"));
+        assert_eq!(
+            Some(Duration::ZERO),
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+        let later = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(30));
+        assert!(parse_retry_after(&later).unwrap() > Duration::from_secs(28));
+        assert_eq!(None, parse_retry_after("soon"));
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let cfg = RetryConfig::default();
+        for retry in 0..4 {
+            let max = Duration::from_millis(500 * 2u64.pow(retry));
+            let delay = cfg.delay(retry, None);
+            assert!(max / 2 <= delay && delay <= max, "{delay:?}");
+        }
+        assert!(cfg.delay(20, None) <= MAX_DELAY);
+        let after = Some(Duration::from_secs(7));
+        assert_eq!(Duration::from_secs(7), cfg.delay(0, after));
+    }
+
+    #[tokio::test]
+    async fn retries_only_transient_failures() {
+        let attempts = Cell::new(0);
+        let failing = |kind| {
+            let attempts = &attempts;
+            move || async move {
+                attempts.set(attempts.get() + 1);
+                Err::<(), _>(io::Error::from(kind))
+            }
+        };
+        let verdict = |e: &io::Error| match e.kind() {
+            io::ErrorKind::TimedOut => Verdict::Retry(Some(Duration::ZERO)),
+            _ => Verdict::Fail,
+        };
+
+        retry(failing(io::ErrorKind::NotFound), verdict)
+            .await
+            .unwrap_err();
+        assert_eq!(1, attempts.replace(0));
+        retry(failing(io::ErrorKind::TimedOut), verdict)
+            .await
+            .unwrap_err();
+        assert_eq!(4, attempts.replace(0));
+
+        let flaky = || async {
+            attempts.set(attempts.get() + 1);
+            match attempts.get() {
+                1 => Err(io::Error::from(io::ErrorKind::TimedOut)),
+                n => Ok(n),
+            }
+        };
+        assert_eq!(2, retry(flaky, verdict).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn shares_the_timeout_between_operations() {
+        let limit = Duration::from_millis(300);
+        *DEADLINE.lock().unwrap() = Some((Instant::now() + limit, limit));
+        let operation = || {
+            timeout(async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                Ok(())
+            })
+        };
+
+        operation().await.unwrap();
+        let error = operation().await.unwrap_err();
+        *DEADLINE.lock().unwrap() = None;
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(RetryError::Timeout(l)) if *l == limit
+        ));
+    }
+}
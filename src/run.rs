@@ -1,36 +1,421 @@
-use anyhow::Result;
-use std::process::{Command, ExitStatus, Output, Stdio};
+use anyhow::{bail, Result};
+use base64::{engine::general_purpose::STANDARD as base64, Engine as _};
+use clap::Args;
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::Path,
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::NamedTempFile;
+use thiserror::Error;
+use tracing::{info, warn};
 
+use crate::audit;
+use crate::diff::{diff, render};
 use crate::env::ProcessEnv;
+use crate::redact::{CiMask, Redactor};
 
-fn run_with_output<F>(env: ProcessEnv, command: Vec<String>, stdio: F) -> Result<Output>
-where
-    F: Fn() -> Stdio,
-{
-    let env = env.into_env();
+#[derive(Args, Clone, Debug)]
+pub struct RunOptions {
+    /// Replace the values of the secrets in the output of the command (also when base64 or URL
+    /// encoded) with `***`. Values shorter than 4 characters are not redacted.
+    #[arg(long)]
+    redact_output: bool,
+
+    /// Ask the CI platform to mask the values of the secrets in its logs before the command is
+    /// started, which also covers output that does not go through `kvenv`.
+    #[arg(long, value_enum, default_value_t)]
+    ci_mask: CiMask,
+}
+
+#[derive(Error, Debug)]
+pub enum FileVarError {
+    #[error("variable '{0}' cannot be passed as a file - it does not exist")]
+    Missing(String),
+    #[error("cannot write variable '{0}' to a file")]
+    Io(String, #[source] std::io::Error),
+    #[error("variable '{0}' does not hold valid base64-encoded binary data")]
+    Binary(String, #[source] base64::DecodeError),
+}
+
+fn write_file_var(name: &str, value: &[u8]) -> Result<NamedTempFile, FileVarError> {
+    let mut b = tempfile::Builder::new();
+    b.prefix("kvenv-").rand_bytes(10);
+    // Prefer in-memory filesystem so that the value never reaches the disk
+    let shm = Path::new("/dev/shm");
+    let file = if shm.is_dir() {
+        b.tempfile_in(shm).or_else(|_| b.tempfile())
+    } else {
+        b.tempfile()
+    };
+    let mut file = file.map_err(|e| FileVarError::Io(name.to_string(), e))?;
+    file.write_all(value)
+        .and_then(|_| file.flush())
+        .map_err(|e| FileVarError::Io(name.to_string(), e))?;
+    Ok(file)
+}
+
+/// Replaces values of the selected variables with paths to private temporary files that hold the
+/// values. Binary variables are decoded before being written. The files are removed when the
+/// returned handles are dropped.
+fn materialize_file_vars(
+    env: &mut HashMap<String, String>,
+    file_vars: &[String],
+    binary_vars: &[String],
+) -> Result<Vec<NamedTempFile>, FileVarError> {
+    let mut files = Vec::with_capacity(file_vars.len());
+    for name in file_vars {
+        let value = env
+            .get_mut(name)
+            .ok_or_else(|| FileVarError::Missing(name.clone()))?;
+        let file = if binary_vars.contains(name) {
+            let data = base64
+                .decode(&value)
+                .map_err(|e| FileVarError::Binary(name.clone(), e))?;
+            write_file_var(name, &data)?
+        } else {
+            write_file_var(name, value.as_bytes())?
+        };
+        *value = file.path().to_string_lossy().into_owned();
+        files.push(file);
+    }
+    Ok(files)
+}
+
+/// Starts the command. The returned files hold the file variables and have to be kept until the
+/// command exits.
+fn spawn(
+    env: ProcessEnv,
+    command: Vec<String>,
+    stdin: Stdio,
+    stdout: Stdio,
+    stderr: Stdio,
+) -> Result<(Child, Vec<NamedTempFile>)> {
+    let file_vars = env.file_vars().to_vec();
+    let binary_vars = env.binary_vars().to_vec();
+    let mut env = env.into_env()?;
+    let files = materialize_file_vars(&mut env, &file_vars, &binary_vars)?;
 
     let child = Command::new(&command[0])
         .args(command.iter().skip(1))
         .env_clear()
         .envs(&env)
-        .stdout(stdio())
-        .stdin(stdio())
-        .stderr(stdio())
+        .stdout(stdout)
+        .stdin(stdin)
+        .stderr(stderr)
         .spawn()?;
+    Ok((child, files))
+}
+
+/// Creates a redactor for the values from the secret store. Binary values are redacted both as
+/// stored (base64) and decoded.
+fn redactor(env: &ProcessEnv) -> Redactor {
+    let mut values: Vec<Vec<u8>> = vec![];
+    for (name, value) in env.secrets() {
+        values.push(value.as_bytes().to_vec());
+        if env.binary_vars().contains(name) {
+            values.extend(base64.decode(value).ok());
+        }
+    }
+    Redactor::new(values.iter().map(Vec::as_slice))
+}
+
+/// Waits for the command to exit. `track` gets `None` before the command is reaped, as its process
+/// id may be reused by another process afterwards.
+fn wait(child: &mut Child, track: &impl Fn(Option<u32>)) -> std::io::Result<ExitStatus> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let (pid, options) = (child.id() as libc::id_t, libc::WEXITED | libc::WNOWAIT);
+    // `WNOWAIT` leaves the exited command to be reaped by `Child::wait`
+    while unsafe { libc::waitid(libc::P_PID, pid, &mut info, options) } != 0 {
+        let e = std::io::Error::last_os_error();
+        if e.kind() != std::io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    track(None);
+    child.wait()
+}
+
+/// Runs the command with its standard output and error passed through the redactor. `track` gets
+/// the process id of the command (see `run_in_env_with`).
+fn run_redacted(
+    env: ProcessEnv,
+    command: Vec<String>,
+    stdout: impl Write + Send,
+    stderr: impl Write + Send,
+    track: impl Fn(Option<u32>),
+) -> Result<ExitStatus> {
+    let redactor = redactor(&env);
+    let (mut child, _files) = spawn(
+        env,
+        command,
+        Stdio::inherit(),
+        Stdio::piped(),
+        Stdio::piped(),
+    )?;
+    track(Some(child.id()));
+    let (child_out, child_err) = (child.stdout.take().unwrap(), child.stderr.take().unwrap());
+    let redactor = &redactor;
+    thread::scope(|s| {
+        let out = s.spawn(move || redactor.forward(child_out, stdout));
+        let err = s.spawn(move || redactor.forward(child_err, stderr));
+        let status = wait(&mut child, &track)?;
+        out.join().expect("the output thread panicked")?;
+        err.join().expect("the output thread panicked")?;
+        Ok(status)
+    })
+}
+
+/// Runs the command and waits for it to exit. `track` gets the process id of the command once it's
+/// started and `None` once it exits, before the process id can be reused.
+fn run_in_env_with(
+    env: ProcessEnv,
+    command: Vec<String>,
+    opts: &RunOptions,
+    track: impl Fn(Option<u32>),
+) -> Result<ExitStatus> {
+    let ci_mask = opts.ci_mask.resolve(|name| std::env::var(name).ok());
+    let values = env.secrets().iter().map(|(_, v)| v.as_str());
+    // Not on stdout, which may be captured or piped to another command
+    eprint!("{}", ci_mask.commands(values));
+    std::io::stderr().flush()?;
+
+    let status = if opts.redact_output || ci_mask == CiMask::Gitlab {
+        run_redacted(env, command, std::io::stdout(), std::io::stderr(), track)?
+    } else {
+        let stdio = Stdio::inherit;
+        let (mut child, _files) = spawn(env, command, stdio(), stdio(), stdio())?;
+        track(Some(child.id()));
+        wait(&mut child, &track)?
+    };
+    audit::command_exit(&status)?;
+    Ok(status)
+}
+
+pub fn run_in_env(env: ProcessEnv, command: Vec<String>, opts: &RunOptions) -> Result<ExitStatus> {
+    run_in_env_with(env, command, opts, |_| {})
+}
+
+/// The process id of the command run by `run_in_env_guarded`, 0 until it's started.
+static GUARDED_PID: AtomicI32 = AtomicI32::new(0);
+/// A signal received by `run_in_env_guarded` before the command was started.
+static PENDING_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+const GUARDED_SIGNALS: [i32; 4] = [libc::SIGINT, libc::SIGQUIT, libc::SIGTERM, libc::SIGHUP];
+
+extern "C" fn forward_signal(signal: libc::c_int) {
+    // The terminal sends `SIGINT` and `SIGQUIT` to the command as well
+    if signal == libc::SIGTERM || signal == libc::SIGHUP {
+        match GUARDED_PID.load(Ordering::SeqCst) {
+            0 => PENDING_SIGNAL.store(signal, Ordering::SeqCst),
+            pid => unsafe {
+                libc::kill(pid, signal);
+            },
+        }
+    }
+}
+
+fn set_signal_handlers(handler: libc::sighandler_t) {
+    for signal in GUARDED_SIGNALS {
+        unsafe { libc::signal(signal, handler) };
+    }
+}
 
-    let output = child.wait_with_output()?;
+/// Runs the command like `run_in_env`, but the signals that would stop `kvenv` do not stop it
+/// before the command exits, so that the caller can clean up afterwards. `SIGTERM` and `SIGHUP`
+/// are forwarded to the command.
+pub fn run_in_env_guarded(
+    env: ProcessEnv,
+    command: Vec<String>,
+    opts: &RunOptions,
+) -> Result<ExitStatus> {
+    // A handler (unlike ignoring the signal) is reset to the default in the command
+    set_signal_handlers(forward_signal as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    let status = run_in_env_with(env, command, opts, |pid| {
+        let Some(pid) = pid else {
+            GUARDED_PID.store(0, Ordering::SeqCst);
+            return;
+        };
+        GUARDED_PID.store(pid as i32, Ordering::SeqCst);
+        match PENDING_SIGNAL.swap(0, Ordering::SeqCst) {
+            0 => {}
+            signal => unsafe {
+                libc::kill(pid as libc::pid_t, signal);
+            },
+        }
+    });
+    GUARDED_PID.store(0, Ordering::SeqCst);
+    set_signal_handlers(libc::SIG_DFL);
+    status
+}
 
-    Ok(output)
+/// Parses durations like `500ms`, `30s`, `5m` or `1h`. Numbers without a unit are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let idx = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(idx);
+    let Ok(value) = value.parse::<u64>() else {
+        bail!("expected a duration like `30s` or `5m`, got '{}'", s)
+    };
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 60 * 60)),
+        _ => bail!("unknown unit '{}' in duration '{}'", unit, s),
+    }
 }
 
-pub fn run_in_env(env: ProcessEnv, command: Vec<String>) -> Result<ExitStatus> {
-    Ok(run_with_output(env, command, Stdio::inherit)?.status)
+/// Parses a duration like `parse_duration`, which must not be zero.
+fn parse_interval(s: &str) -> Result<Duration> {
+    match parse_duration(s)? {
+        Duration::ZERO => bail!("the interval must be longer than zero"),
+        interval => Ok(interval),
+    }
+}
+
+/// Parses signal names like `TERM` or `SIGHUP`.
+fn parse_signal(s: &str) -> Result<i32> {
+    let name = s.to_ascii_uppercase();
+    let signal = match name.strip_prefix("SIG").unwrap_or(&name) {
+        "HUP" => libc::SIGHUP,
+        "INT" => libc::SIGINT,
+        "QUIT" => libc::SIGQUIT,
+        "TERM" => libc::SIGTERM,
+        "USR1" => libc::SIGUSR1,
+        "USR2" => libc::SIGUSR2,
+        "KILL" => libc::SIGKILL,
+        _ => bail!("unsupported signal '{}'", s),
+    };
+    Ok(signal)
+}
+
+#[derive(Args, Debug)]
+pub struct WatchOptions {
+    /// Download the environment again in the specified interval (e.g. `30s`, `5m` or `1h`) and
+    /// restart the command when the variables from the secret store change.
+    #[arg(long, value_parser = parse_interval)]
+    watch: Option<Duration>,
+
+    /// The signal sent to the command to stop it before a restart.
+    #[arg(long, value_parser = parse_signal, default_value = "TERM", requires = "watch")]
+    restart_signal: i32,
+
+    /// How long the command has to exit after `restart-signal` before it is killed.
+    #[arg(long, value_parser = parse_duration, default_value = "10s", requires = "watch")]
+    grace_period: Duration,
+}
+
+impl WatchOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.watch.is_some()
+    }
+}
+
+/// Sleeps until the deadline, returning early (with `true`) if the command exits.
+fn wait_until(deadline: Instant, command: &thread::JoinHandle<Result<ExitStatus>>) -> bool {
+    while Instant::now() < deadline {
+        if command.is_finished() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100).min(deadline - Instant::now()));
+    }
+    command.is_finished()
+}
+
+/// Sends the signal to the command unless it has exited. The command is not reaped while the lock
+/// is held, so its process id cannot be reused by another process.
+fn stop(pid: &Mutex<Option<u32>>, signal: i32) {
+    if let Some(pid) = *pid.lock().unwrap() {
+        unsafe { libc::kill(pid as libc::pid_t, signal) };
+    }
+}
+
+/// Runs the command like `run_in_env`, but `download`s the environment again every `watch`
+/// interval. When the variables from the secret store change, the command is stopped (with
+/// `restart-signal`, then `SIGKILL` after `grace-period`) and started again with the new
+/// environment. Returns when the command exits on its own.
+pub fn run_watched<F>(
+    mut env: ProcessEnv,
+    download: F,
+    command: Vec<String>,
+    opts: &RunOptions,
+    watch: &WatchOptions,
+) -> Result<ExitStatus>
+where
+    F: Fn() -> Result<ProcessEnv>,
+{
+    let interval = watch.watch.expect("watch mode is enabled");
+    loop {
+        let pid = Arc::new(Mutex::new(None));
+        let (tx, rx) = mpsc::channel();
+        let running = {
+            let (env, command, opts) = (env.clone(), command.clone(), opts.clone());
+            let pid = pid.clone();
+            thread::spawn(move || {
+                run_in_env_with(env, command, &opts, |p| {
+                    *pid.lock().unwrap() = p;
+                    let _ = tx.send(());
+                })
+            })
+        };
+        if rx.recv().is_err() {
+            return running.join().expect("the command thread panicked");
+        }
+
+        let new_env = loop {
+            if wait_until(Instant::now() + interval, &running) {
+                return running.join().expect("the command thread panicked");
+            }
+            let new_env = match download() {
+                Ok(new_env) => new_env,
+                Err(e) => {
+                    warn!(error = %format!("{e:#}"), "cannot download the environment");
+                    continue;
+                }
+            };
+            let changes = diff(env.secrets(), new_env.secrets());
+            if !changes.is_empty() {
+                let changes: Vec<_> = render(&changes, false).lines().map(String::from).collect();
+                info!(
+                    changes = %changes.join(", "),
+                    "variables changed, restarting the command"
+                );
+                break new_env;
+            }
+        };
+
+        stop(&pid, watch.restart_signal);
+        if !wait_until(Instant::now() + watch.grace_period, &running) {
+            stop(&pid, libc::SIGKILL);
+        }
+        running.join().expect("the command thread panicked")?;
+        env = new_env;
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::{cell::Cell, process::Output};
+
+    fn run_with_output<F>(env: ProcessEnv, command: Vec<String>, stdio: F) -> Result<Output>
+    where
+        F: Fn() -> Stdio,
+    {
+        let (child, _files) = spawn(env, command, stdio(), stdio(), stdio())?;
+        let output = child.wait_with_output()?;
+
+        Ok(output)
+    }
+
     #[test]
     fn runs_process_in_correct_env() {
         let env = ProcessEnv::fresh(
@@ -47,6 +432,154 @@ mod tests {
         assert_eq!("ENV=A\nKV=B\n", &stdout);
     }
 
+    #[test]
+    fn passes_file_vars_as_files() {
+        let env = ProcessEnv::fresh(
+            vec![],
+            vec![
+                ("KV".to_string(), "B".to_string()),
+                ("FILE".to_string(), "secret\nvalue".to_string()),
+            ],
+            vec![],
+        )
+        .with_file_vars(vec!["FILE".to_string()]);
+
+        let output = run_with_output(
+            env,
+            vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                "echo \"$FILE\"; cat \"$FILE\"; echo; stat -c %a \"$FILE\"".to_string(),
+            ],
+            Stdio::piped,
+        )
+        .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let mut lines = stdout.lines();
+        let path = lines.next().unwrap();
+        assert_eq!(Some("secret"), lines.next());
+        assert_eq!(Some("value"), lines.next());
+        assert_eq!(Some("600"), lines.next());
+        assert!(!Path::new(path).exists());
+    }
+
+    #[test]
+    fn decodes_binary_file_vars() {
+        let env = ProcessEnv::fresh(
+            vec![],
+            vec![
+                ("BIN".to_string(), base64.encode([0xff, 0x00, 0x41])),
+                ("BIN_B64".to_string(), base64.encode([0xff, 0x00, 0x41])),
+            ],
+            vec![],
+        )
+        .with_file_vars(vec!["BIN".to_string()])
+        .with_binary_vars(vec!["BIN".to_string(), "BIN_B64".to_string()]);
+
+        let output = run_with_output(
+            env,
+            vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                "echo \"$BIN_B64\"; od -An -tx1 \"$BIN\"".to_string(),
+            ],
+            Stdio::piped,
+        )
+        .unwrap();
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let mut lines = stdout.lines();
+        assert_eq!(Some("/wBB"), lines.next());
+        assert_eq!(Some("ff 00 41"), lines.next().map(str::trim));
+    }
+
+    #[test]
+    fn redacts_output() {
+        let env = ProcessEnv::fresh(
+            vec![("ENV".to_string(), "public".to_string())],
+            vec![
+                ("KV".to_string(), "p@ssword".to_string()),
+                ("BIN".to_string(), base64.encode("binary")),
+            ],
+            vec![],
+        )
+        .with_binary_vars(vec!["BIN".to_string()]);
+
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        let status = run_redacted(
+            env,
+            vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                "echo \"$ENV $KV\"; printf binary >&2; exit 3".to_string(),
+            ],
+            &mut stdout,
+            &mut stderr,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(Some(3), status.code());
+        assert_eq!("public ***\n", String::from_utf8(stdout).unwrap());
+        assert_eq!("***", String::from_utf8(stderr).unwrap());
+    }
+
+    #[test]
+    fn parses_durations_and_signals() {
+        assert_eq!(Duration::from_millis(500), parse_duration("500ms").unwrap());
+        assert_eq!(Duration::from_secs(30), parse_duration("30").unwrap());
+        assert_eq!(Duration::from_secs(300), parse_duration("5m").unwrap());
+        assert_eq!(Duration::from_secs(7200), parse_duration("2h").unwrap());
+        assert!(parse_duration("5d").is_err());
+        assert!(parse_duration("m").is_err());
+        assert_eq!(Duration::from_secs(1), parse_interval("1s").unwrap());
+        assert!(parse_interval("0s").is_err());
+
+        assert_eq!(libc::SIGTERM, parse_signal("TERM").unwrap());
+        assert_eq!(libc::SIGHUP, parse_signal("sighup").unwrap());
+        assert!(parse_signal("SIGFOO").is_err());
+    }
+
+    #[test]
+    fn restarts_on_change() {
+        let downloads = Cell::new(0);
+        let download = || {
+            downloads.set(downloads.get() + 1);
+            // The value changes on the second refresh, the command exits when it sees it
+            let value = if downloads.get() < 3 { "old" } else { "new" };
+            Ok(ProcessEnv::fresh(
+                vec![],
+                vec![("KV".to_string(), value.to_string())],
+                vec![],
+            ))
+        };
+        let watch = WatchOptions {
+            watch: Some(Duration::from_millis(200)),
+            restart_signal: libc::SIGTERM,
+            grace_period: Duration::from_millis(200),
+        };
+        let opts = RunOptions {
+            redact_output: false,
+            ci_mask: CiMask::None,
+        };
+        let command = vec![
+            "/bin/sh".to_string(),
+            "-c".to_string(),
+            "if [ \"$KV\" = new ]; then exit 7; fi; trap '' TERM; while true; do sleep 0.05; done"
+                .to_string(),
+        ];
+
+        let status = run_watched(download().unwrap(), download, command, &opts, &watch).unwrap();
+        assert_eq!(Some(7), status.code());
+        assert_eq!(3, downloads.get());
+    }
+
+    #[test]
+    fn fails_on_missing_file_var() {
+        let env =
+            ProcessEnv::fresh(vec![], vec![], vec![]).with_file_vars(vec!["MISSING".to_string()]);
+
+        assert!(run_with_output(env, vec!["env".to_string()], Stdio::piped).is_err());
+    }
+
     #[test]
     fn fails_expectedly() {
         let env = || {
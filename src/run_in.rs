@@ -1,9 +1,11 @@
 use anyhow::Result;
-use clap::{arg, Args};
+use clap::Args;
 use thiserror::Error;
 
+use crate::audit;
 use crate::env::{download_env, EnvConfig};
-use crate::run;
+use crate::retry;
+use crate::run::{self, RunOptions, WatchOptions};
 
 #[derive(Error, Debug)]
 pub enum RunInError {
@@ -19,16 +21,31 @@ pub struct RunIn {
     #[command(flatten)]
     env: EnvConfig,
 
+    #[command(flatten)]
+    run: RunOptions,
+
+    #[command(flatten)]
+    watch: WatchOptions,
+
     /// The command to execute
     #[arg(name = "COMMAND", required = true)]
     command: Vec<String>,
 }
 
 pub fn run_in(cfg: RunIn) -> Result<std::convert::Infallible> {
-    let env = download_env(cfg.env, false).map_err(RunInError::LoadError)?;
+    audit::set_command(&cfg.command);
+    let env = download_env(cfg.env.clone(), false).map_err(RunInError::LoadError)?;
 
-    let status = run::run_in_env(env, cfg.command)
-        .map_err(|x| anyhow::Error::new(RunInError::RunError(x)))?;
+    let status = if cfg.watch.is_enabled() {
+        let download = || {
+            retry::restart_deadline();
+            download_env(cfg.env.clone(), false)
+        };
+        run::run_watched(env, download, cfg.command, &cfg.run, &cfg.watch)
+    } else {
+        run::run_in_env(env, cfg.command, &cfg.run)
+    };
+    let status = status.map_err(|x| anyhow::Error::new(RunInError::RunError(x)))?;
     if status.success() {
         std::process::exit(0)
     } else if let Some(code) = status.code() {
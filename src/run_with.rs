@@ -1,20 +1,33 @@
 use anyhow::Result;
-use clap::{arg, Args, ValueHint};
+use clap::{ArgGroup, Args, ValueHint};
 use std::{
-    fs,
+    fs, io,
+    io::{Read, Write},
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
     path::{Path, PathBuf},
+    process::ExitStatus,
 };
 use thiserror::Error;
+use tracing::warn;
 
-use crate::env::ProcessEnv;
-use crate::run;
+use crate::agent::{self, SocketConfig};
+use crate::audit;
+use crate::env::{CacheFileError, ProcessEnv};
+use crate::integrity::IntegrityConfig;
+use crate::keyring_store::{self, KeyringConfig};
+use crate::logging;
+use crate::run::{self, RunOptions};
 
 #[derive(Error, Debug)]
 pub enum RunWithError {
     #[error("cannot load environment file")]
-    Load(#[from] serde_json::error::Error),
+    Load(#[from] CacheFileError),
     #[error("cannot load environment file - io error")]
     Io(#[source] std::io::Error),
+    #[error("refusing to load '{0}' - it is a symbolic link")]
+    Symlink(PathBuf),
+    #[error("refusing to load '{0}' - it must be owned by you and not accessible by others (mode {1:o}, expected 600)")]
+    Permissions(PathBuf, u32),
     #[error("cannot remove the env file")]
     Cleanup(#[source] std::io::Error),
     #[error("cannot run the specified command")]
@@ -24,36 +37,162 @@ pub enum RunWithError {
 /// Runs the command with the specified argument using cached environment.
 #[derive(Args, Debug)]
 #[command(name = "run-with")]
+#[command(group = ArgGroup::new("source").required(true).multiple(false))]
 pub struct RunWith {
     /// Path to the environment file created with `cache` command.
-    #[arg(short, long, value_parser, value_hint = ValueHint::FilePath)]
-    env_file: PathBuf,
+    #[arg(short, long, value_parser, value_hint = ValueHint::FilePath, group = "source")]
+    env_file: Option<PathBuf>,
 
-    /// If set, the env file will be removed after execution.
+    /// The name of the environment stored in the running `kvenv agent` with `cache --agent`.
+    #[arg(long, group = "source")]
+    agent: Option<String>,
+
+    #[command(flatten)]
+    socket: SocketConfig,
+
+    /// The handle of the environment stored in the OS keyring with `cache --store keyring`.
+    #[arg(long, group = "source")]
+    handle: Option<String>,
+
+    #[command(flatten)]
+    keyring: KeyringConfig,
+
+    #[command(flatten)]
+    integrity: IntegrityConfig,
+
+    /// If set, the env file (or the environment in the agent or the keyring) will be removed after
+    /// execution, also when the command fails or `kvenv` is interrupted. The file is overwritten
+    /// before it is removed.
     #[arg(short, long)]
     cleanup: bool,
 
+    #[command(flatten)]
+    run: RunOptions,
+
     /// The command to execute
     #[arg(name = "COMMAND", required = true, last = true)]
     command: Vec<String>,
 }
 
-fn load_env(path: &Path) -> Result<ProcessEnv> {
-    let file = fs::File::open(path).map_err(RunWithError::Io)?;
-    let env = ProcessEnv::from_reader(&file).map_err(RunWithError::Load)?;
+/// Opens the env file, refusing symbolic links and files others could have read or modified.
+fn open_env_file(path: &Path) -> Result<fs::File, RunWithError> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ELOOP) => RunWithError::Symlink(path.to_owned()),
+            _ => RunWithError::Io(e),
+        })?;
+    let meta = file.metadata().map_err(RunWithError::Io)?;
+    let mode = meta.mode() & 0o777;
+    if mode & 0o077 != 0 || meta.uid() != unsafe { libc::getuid() } {
+        return Err(RunWithError::Permissions(path.to_owned(), mode));
+    }
+    Ok(file)
+}
+
+/// Loads the env file, verifying it with the integrity `key` (if any).
+pub fn load_env(path: &Path, key: Option<&[u8]>) -> Result<ProcessEnv> {
+    let file = open_env_file(path)?;
+    let env = ProcessEnv::from_reader(&file, key).map_err(RunWithError::Load)?;
     Ok(env)
 }
 
-pub fn run_with(cfg: RunWith) -> Result<std::convert::Infallible> {
-    let env = load_env(&cfg.env_file)?;
+/// Overwrites the env file with zeros before removing it, so that the secrets do not stay in the
+/// freed blocks (on file systems that overwrite in place).
+fn remove_env_file(path: &Path) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)?;
+    let len = file.metadata()?.len();
+    io::copy(&mut io::repeat(0).take(len), &mut file)?;
+    file.flush()?;
+    file.sync_all()?;
+    fs::remove_file(path)
+}
 
-    let status =
-        run::run_in_env(env, cfg.command).map_err(|x| anyhow::Error::new(RunWithError::Run(x)))?;
-    if status.success() {
-        if cfg.cleanup {
-            fs::remove_file(&cfg.env_file).map_err(RunWithError::Cleanup)?;
+enum Source {
+    File(PathBuf),
+    Agent(String),
+    Keyring(String),
+}
+
+impl Source {
+    fn describe(&self) -> String {
+        match self {
+            Source::File(path) => format!("env file '{}'", path.display()),
+            Source::Agent(name) => format!("agent environment '{name}'"),
+            Source::Keyring(handle) => format!("keyring handle '{handle}'"),
+        }
+    }
+}
+
+impl RunWith {
+    fn source(&self) -> Source {
+        match (&self.env_file, &self.agent, &self.handle) {
+            (Some(path), _, _) => Source::File(path.clone()),
+            (_, Some(name), _) => Source::Agent(name.clone()),
+            (_, _, Some(handle)) => Source::Keyring(handle.clone()),
+            (None, None, None) => unreachable!("clap requires one of the options"),
         }
+    }
+}
+
+impl RunWith {
+    fn load(&self, source: &Source) -> Result<ProcessEnv> {
+        let env = match source {
+            Source::File(path) => load_env(path, self.integrity.key(&self.socket)?.as_deref())?,
+            Source::Agent(name) => agent::load_env(&self.socket, name)?,
+            Source::Keyring(handle) => keyring_store::load_env(&self.keyring, handle)?,
+        };
+        logging::redact_values(env.secrets());
+        audit::environment(&source.describe(), env.secrets())?;
+        Ok(env)
+    }
 
+    fn remove(&self, source: Source) -> Result<()> {
+        match source {
+            Source::File(path) => Ok(remove_env_file(&path).map_err(RunWithError::Cleanup)?),
+            Source::Agent(name) => agent::flush(&self.socket, Some(name)),
+            Source::Keyring(handle) => keyring_store::delete_env(&self.keyring, &handle),
+        }
+    }
+}
+
+/// Runs the command in the environment from the `source`, which is removed afterwards with
+/// `--cleanup`. A source that cannot be loaded (e.g. refused for its owner or mode, or failing the
+/// integrity check) is left as it is.
+fn run_from(cfg: &RunWith, source: Source) -> Result<ExitStatus> {
+    let env = cfg.load(&source)?;
+    let command = cfg.command.clone();
+    let status = if cfg.cleanup {
+        // Keep `kvenv` alive until the command exits, so that the environment is removed
+        run::run_in_env_guarded(env, command, &cfg.run)
+    } else {
+        run::run_in_env(env, command, &cfg.run)
+    }
+    .map_err(|x| anyhow::Error::new(RunWithError::Run(x)));
+
+    if cfg.cleanup {
+        let removed = cfg.remove(source);
+        match (&status, removed) {
+            (Ok(_), removed) => removed?,
+            (Err(_), Err(e)) => warn!(
+                error = %format!("{e:#}"),
+                "cannot remove the environment"
+            ),
+            (Err(_), Ok(())) => {}
+        }
+    }
+    status
+}
+
+pub fn run_with(cfg: RunWith) -> Result<std::convert::Infallible> {
+    audit::set_command(&cfg.command);
+    let status = run_from(&cfg, cfg.source())?;
+    if status.success() {
         std::process::exit(0)
     } else if let Some(code) = status.code() {
         std::process::exit(code)
@@ -61,3 +200,71 @@ pub fn run_with(cfg: RunWith) -> Result<std::convert::Infallible> {
         std::process::exit(-1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn loads_only_private_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("env.json");
+        fs::write(&path, r#"{"from_kv":[["KV","secret"]],"masked":[]}"#).unwrap();
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            open_env_file(&path),
+            Err(RunWithError::Permissions(_, 0o644))
+        ));
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(
+            vec![("KV".to_string(), "secret".to_string())],
+            load_env(&path, None).unwrap().secrets()
+        );
+
+        let link = dir.path().join("link.json");
+        std::os::unix::fs::symlink(&path, &link).unwrap();
+        assert!(matches!(
+            open_env_file(&link),
+            Err(RunWithError::Symlink(_))
+        ));
+
+        remove_env_file(&link).unwrap_err();
+        remove_env_file(&path).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn keeps_refused_files_on_cleanup() {
+        #[derive(clap::Parser)]
+        struct Cli {
+            #[command(flatten)]
+            run_with: RunWith,
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("env.json");
+        let contents = r#"{"from_kv":[["KV","secret"]],"masked":[]}"#;
+        fs::write(&path, contents).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let args = [
+            "run-with",
+            "--cleanup",
+            "--env-file",
+            path.to_str().unwrap(),
+        ];
+        let cfg = <Cli as clap::Parser>::try_parse_from(args.into_iter().chain(["--", "true"]))
+            .unwrap()
+            .run_with;
+
+        let error = run_from(&cfg, cfg.source()).unwrap_err();
+
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(RunWithError::Permissions(_, 0o644))
+        ));
+        assert_eq!(contents, fs::read_to_string(&path).unwrap());
+    }
+}
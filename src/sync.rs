@@ -0,0 +1,71 @@
+use anyhow::Result;
+use clap::Args;
+use thiserror::Error;
+
+use crate::diff::{diff, render, Change};
+use crate::env::{parse_location, read_location, write_location, Location, StoreConfig};
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("cannot read the source")]
+    ReadSource(#[source] anyhow::Error),
+    #[error("cannot read the destination")]
+    ReadDestination(#[source] anyhow::Error),
+    #[error("cannot write the destination")]
+    Write(#[source] anyhow::Error),
+}
+
+/// Copies the variables from one location to another, e.g. between two secret stores. A location
+/// is written like a secret reference, e.g. `azkv://keyvault/app` for a JSON secret and
+/// `azkv://keyvault/app-*` for the secrets with the prefix, or is a path to a file. The options of
+/// the secret stores (e.g. `--vault-address`) are used to access them.
+#[derive(Args, Debug)]
+pub struct Sync {
+    #[command(flatten)]
+    store: StoreConfig,
+
+    /// The location to read the variables from. Files can be dotenv, JSON, YAML or TOML.
+    #[arg(long, value_parser = parse_location)]
+    from: Location,
+
+    /// The location to write the variables to. Files are written as JSON if the name ends with
+    /// `.json` and as dotenv otherwise.
+    #[arg(long, value_parser = parse_location)]
+    to: Location,
+
+    /// Delete the variables that are present only in the destination. They are kept by default.
+    #[arg(long)]
+    prune: bool,
+
+    /// Only print the changes, without writing anything.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+pub fn run_sync(cfg: Sync) -> Result<()> {
+    let source = read_location(&cfg.store, &cfg.from, false).map_err(SyncError::ReadSource)?;
+    let current = read_location(&cfg.store, &cfg.to, true).map_err(SyncError::ReadDestination)?;
+
+    let changes: Vec<_> = diff(&current.vars, &source.vars)
+        .into_iter()
+        .filter(|c| cfg.prune || !matches!(c, Change::Removed(..)))
+        .collect();
+    let mut set = vec![];
+    let mut delete = vec![];
+    for change in &changes {
+        match change {
+            Change::Added(n, v) | Change::Changed(n, _, v) => {
+                set.push((n.to_string(), v.to_string()))
+            }
+            Change::Removed(n, _) => delete.push(n.to_string()),
+        }
+    }
+    for line in render(&changes, false).lines() {
+        let prefix = if cfg.dry_run { "(dry run) " } else { "" };
+        println!("{prefix}{line}");
+    }
+    if !cfg.dry_run && !(set.is_empty() && delete.is_empty()) {
+        write_location(&cfg.store, &cfg.to, current, set, &delete).map_err(SyncError::Write)?;
+    }
+    Ok(())
+}
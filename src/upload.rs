@@ -0,0 +1,162 @@
+use anyhow::Result;
+use clap::{Args, ValueHint};
+use std::{io::Read, path::PathBuf};
+use thiserror::Error;
+
+use crate::agent::SocketConfig;
+use crate::env::{read_env_file, upload_env, EnvConfig, Upload};
+use crate::integrity::IntegrityConfig;
+use crate::run_with::load_env;
+
+#[derive(Error, Debug)]
+pub enum UploadError {
+    #[error("cannot read the variables")]
+    Read(#[source] anyhow::Error),
+    #[error("cannot store the variables in the secret store")]
+    Write(#[source] anyhow::Error),
+}
+
+#[derive(Args, Debug)]
+pub struct UploadOptions {
+    /// Only print the secrets that would be written, without writing anything.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// Sets variables in the secret store. With `secret-name`, the variables are added to the JSON
+/// secret, with `secret-prefix` a separate secret is written for every variable.
+#[derive(Args, Debug)]
+pub struct Set {
+    #[command(flatten)]
+    env: EnvConfig,
+
+    #[command(flatten)]
+    opts: UploadOptions,
+
+    /// The variables to set, as `NAME=VALUE`. If the value is `-`, it is read from the standard
+    /// input.
+    #[arg(name = "VARS", required = true, value_parser = parse_var)]
+    vars: Vec<(String, String)>,
+}
+
+/// Stores variables from a dotenv, JSON, YAML or TOML file in the secret store.
+#[derive(Args, Debug)]
+pub struct Import {
+    #[command(flatten)]
+    env: EnvConfig,
+
+    #[command(flatten)]
+    opts: UploadOptions,
+
+    /// If set, the variables are added to the JSON secret instead of replacing its contents.
+    #[arg(long)]
+    merge: bool,
+
+    /// The file with the variables.
+    #[arg(name = "FILE", value_parser, value_hint = ValueHint::FilePath)]
+    file: PathBuf,
+}
+
+/// Stores the variables from an environment file created with `cache` command in the secret
+/// store, e.g. to promote secrets from one store to another.
+#[derive(Args, Debug)]
+pub struct Push {
+    #[command(flatten)]
+    env: EnvConfig,
+
+    #[command(flatten)]
+    opts: UploadOptions,
+
+    /// If set, the variables are added to the JSON secret instead of replacing its contents.
+    #[arg(long)]
+    merge: bool,
+
+    /// Path to the environment file created with `cache` command.
+    #[arg(long, value_parser, value_hint = ValueHint::FilePath)]
+    env_file: PathBuf,
+
+    #[command(flatten)]
+    integrity: IntegrityConfig,
+
+    #[command(flatten)]
+    socket: SocketConfig,
+}
+
+fn parse_var(s: &str) -> Result<(String, String)> {
+    let Some((name, value)) = s.split_once('=') else {
+        anyhow::bail!("expected `NAME=VALUE`, got '{}'", s)
+    };
+    Ok((name.to_string(), value.to_string()))
+}
+
+fn render(uploads: &[Upload], dry_run: bool) -> String {
+    let mut out = String::new();
+    for upload in uploads {
+        if dry_run {
+            out.push_str("(dry run) ");
+        }
+        out.push_str(&format!("{}: {}\n", upload.secret, upload.vars.join(", ")));
+    }
+    out
+}
+
+fn upload(
+    env: EnvConfig,
+    vars: Vec<(String, String)>,
+    merge: bool,
+    opts: UploadOptions,
+) -> Result<()> {
+    let uploads = upload_env(env, vars, merge, opts.dry_run).map_err(UploadError::Write)?;
+    print!("{}", render(&uploads, opts.dry_run));
+    Ok(())
+}
+
+pub fn run_set(cfg: Set) -> Result<()> {
+    let mut vars = cfg.vars;
+    if let Some((_, value)) = vars.iter_mut().find(|(_, v)| v == "-") {
+        let mut stdin = String::new();
+        std::io::stdin()
+            .read_to_string(&mut stdin)
+            .map_err(|e| UploadError::Read(e.into()))?;
+        *value = stdin.strip_suffix('\n').unwrap_or(&stdin).to_string();
+    }
+    upload(cfg.env, vars, true, cfg.opts)
+}
+
+pub fn run_import(cfg: Import) -> Result<()> {
+    let vars = read_env_file(&cfg.file).map_err(UploadError::Read)?;
+    upload(cfg.env, vars, cfg.merge, cfg.opts)
+}
+
+pub fn run_push(cfg: Push) -> Result<()> {
+    let env = cfg
+        .integrity
+        .key(&cfg.socket)
+        .and_then(|key| load_env(&cfg.env_file, key.as_deref()))
+        .map_err(UploadError::Read)?;
+    upload(cfg.env, env.secrets().to_vec(), cfg.merge, cfg.opts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_vars() {
+        assert_eq!(
+            ("A".to_string(), "b=c".to_string()),
+            parse_var("A=b=c").unwrap()
+        );
+        assert!(parse_var("A").is_err());
+    }
+
+    #[test]
+    fn renders_uploads() {
+        let uploads = vec![Upload {
+            secret: "app".to_string(),
+            vars: vec!["A".to_string(), "B".to_string()],
+        }];
+        assert_eq!("app: A, B\n", render(&uploads, false));
+        assert_eq!("(dry run) app: A, B\n", render(&uploads, true));
+    }
+}